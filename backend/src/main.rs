@@ -7,17 +7,22 @@ use actix_web::{
 };
 use env_logger::Env;
 use log::{error, info};
+use mandelatar_core::catalog::Catalog;
 use mandelatar_core::image_params::{
-    ImageParams, ImagePostProcessConfig, OUTPUT_HEIGHT, OUTPUT_WIDTH,
+    self, ImageParams, ImagePostProcessConfig, OutputLimits, RandOptions, MAX_RENDER_WORK,
+    MAX_TOKEN_LEN, OUTPUT_HEIGHT, OUTPUT_WIDTH,
 };
 use mandelatar_core::mandelbrot;
 use mandelatar_core::post_processing;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
 use url::Url;
 
-const MAX_B64_LEN: usize = 500;
-
 fn parse_image_q_params(
     req: &HttpRequest,
+    output_limits: &OutputLimits,
 ) -> std::result::Result<ImagePostProcessConfig, errors::UserError> {
     let url = Url::parse(
         format!(
@@ -37,7 +42,7 @@ fn parse_image_q_params(
         .into_owned()
         .collect::<Vec<(String, String)>>();
 
-    ImagePostProcessConfig::from_query_params(&query_pairs).map_err(|e| {
+    ImagePostProcessConfig::from_query_params(&query_pairs, output_limits).map_err(|e| {
         error!("Invalid q params: {}", e);
         errors::UserError::ValidationError {
             message: "Invalid query params provided".to_string(),
@@ -45,25 +50,60 @@ fn parse_image_q_params(
     })
 }
 
+// The `?seed=` option, for the same random image every time
+fn parse_seed_q_param(req: &HttpRequest) -> std::result::Result<Option<u64>, errors::UserError> {
+    let query =
+        web::Query::<HashMap<String, String>>::from_query(req.query_string()).map_err(|e| {
+            error!("Invalid q params: {}", e);
+            errors::UserError::ValidationError {
+                message: "Invalid query params provided".to_string(),
+            }
+        })?;
+
+    query
+        .get("seed")
+        .map(|seed| seed.parse::<u64>())
+        .transpose()
+        .map_err(|e| {
+            error!("Invalid seed: {}", e);
+            errors::UserError::ValidationError {
+                message: "Invalid seed provided".to_string(),
+            }
+        })
+}
+
 #[get("/i1/random")]
-async fn get_random_from_worker_failover() -> Result<HttpResponse, errors::UserError> {
-    get_random().await
+async fn get_random_from_worker_failover(
+    rand_options: web::Data<RandOptions>,
+    req: HttpRequest,
+) -> Result<HttpResponse, errors::UserError> {
+    get_random(rand_options, req).await
 }
 
 #[get("/api/v1/random", name = "random_image")]
-async fn get_random_direct() -> Result<HttpResponse, errors::UserError> {
-    get_random().await
+async fn get_random_direct(
+    rand_options: web::Data<RandOptions>,
+    req: HttpRequest,
+) -> Result<HttpResponse, errors::UserError> {
+    get_random(rand_options, req).await
 }
 
-async fn get_random() -> Result<HttpResponse, errors::UserError> {
-    let img_params = ImageParams::new_from_rand((OUTPUT_WIDTH, OUTPUT_HEIGHT));
-    let imp_bin = bincode::serialize(&img_params).map_err(|e| {
+async fn get_random(
+    rand_options: web::Data<RandOptions>,
+    req: HttpRequest,
+) -> Result<HttpResponse, errors::UserError> {
+    // Seeded images ignore `rand_options`, to match the worker's
+    let img_params = match parse_seed_q_param(&req)? {
+        Some(seed) => ImageParams::new_from_seed((OUTPUT_WIDTH, OUTPUT_HEIGHT), seed),
+        None => {
+            ImageParams::new_from_rand_with_options((OUTPUT_WIDTH, OUTPUT_HEIGHT), &rand_options)
+        }
+    };
+    let b64 = img_params.to_token().map_err(|e| {
         error!("Failed to serialize img params: {}", e);
         errors::UserError::InternalError
     })?;
 
-    let b64 = base64::encode_config(imp_bin, base64::URL_SAFE);
-
     Ok(HttpResponse::build(StatusCode::TEMPORARY_REDIRECT)
         .insert_header((header::LOCATION, format!("/api/v1/img/{}.png", b64)))
         .finish())
@@ -72,26 +112,29 @@ async fn get_random() -> Result<HttpResponse, errors::UserError> {
 #[get("/i1/i/{img_b64}")]
 async fn get_image_from_worker_failover(
     path: web::Path<String>,
+    output_limits: web::Data<OutputLimits>,
     req: HttpRequest,
 ) -> Result<HttpResponse, errors::UserError> {
-    get_image(path, req).await
+    get_image(path, output_limits, req).await
 }
 
 #[get("/api/v1/img/{img_b64}", name = "get_image")]
 async fn get_image_direct(
     path: web::Path<String>,
+    output_limits: web::Data<OutputLimits>,
     req: HttpRequest,
 ) -> Result<HttpResponse, errors::UserError> {
-    get_image(path, req).await
+    get_image(path, output_limits, req).await
 }
 
 async fn get_image(
     path: web::Path<String>,
+    output_limits: web::Data<OutputLimits>,
     req: HttpRequest,
 ) -> Result<HttpResponse, errors::UserError> {
     let img_b64: String = path.into_inner();
 
-    if img_b64.trim().is_empty() || img_b64.len() > MAX_B64_LEN {
+    if img_b64.trim().is_empty() || img_b64.len() > MAX_TOKEN_LEN {
         return Err(errors::UserError::ValidationError {
             message: "invalid base64 provided".to_string(),
         });
@@ -99,27 +142,73 @@ async fn get_image(
 
     let img_b64 = img_b64.replace(".png", "");
 
-    let bin = base64::decode_config(img_b64, base64::URL_SAFE).map_err(|e| {
-        error!("Failed to decode from b64: {}", e);
+    let img_params = ImageParams::from_token(&img_b64).map_err(|e| {
+        error!("Failed to deserialize from b64: {}", e);
         errors::UserError::ValidationError {
             message: "Invalid base64 provided".to_string(),
         }
     })?;
 
-    let img_params: ImageParams = bincode::deserialize(&bin).map_err(|e| {
-        error!("Failed to deserialize from b64: {}", e);
+    render_image(img_params, &output_limits, &req)
+}
+
+#[get("/i1/id/{identity}")]
+async fn get_identity_image_from_worker_failover(
+    path: web::Path<String>,
+    output_limits: web::Data<OutputLimits>,
+    req: HttpRequest,
+) -> Result<HttpResponse, errors::UserError> {
+    get_identity_image(path, output_limits, req).await
+}
+
+#[get("/api/v1/id/{identity}", name = "get_identity_image")]
+async fn get_identity_image_direct(
+    path: web::Path<String>,
+    output_limits: web::Data<OutputLimits>,
+    req: HttpRequest,
+) -> Result<HttpResponse, errors::UserError> {
+    get_identity_image(path, output_limits, req).await
+}
+
+async fn get_identity_image(
+    path: web::Path<String>,
+    output_limits: web::Data<OutputLimits>,
+    req: HttpRequest,
+) -> Result<HttpResponse, errors::UserError> {
+    // Already percent-decoded by the `web::Path` extractor
+    let identity: String = path.into_inner();
+    let identity = image_params::identity_from_path(&identity).map_err(|e| {
+        error!("Invalid identity: {}", e);
         errors::UserError::ValidationError {
-            message: "Invalid base64 provided".to_string(),
+            message: "invalid identity provided".to_string(),
         }
     })?;
 
-    let mut png_bytes = mandelbrot::create_png(&img_params).map_err(|e| {
+    render_image(ImageParams::from_identity(identity), &output_limits, &req)
+}
+
+fn render_image(
+    mut img_params: ImageParams,
+    output_limits: &OutputLimits,
+    req: &HttpRequest,
+) -> Result<HttpResponse, errors::UserError> {
+    let mut q_params = parse_image_q_params(req, output_limits)?;
+    q_params.apply_to_image_params(&mut img_params, output_limits);
+
+    img_params
+        .validate_work(output_limits, MAX_RENDER_WORK)
+        .map_err(|e| {
+            error!("Image too expensive: {}", e);
+            errors::UserError::ValidationError {
+                message: "Image too expensive to render".to_string(),
+            }
+        })?;
+
+    let mut png_bytes = mandelbrot::create_png_within(&img_params, output_limits).map_err(|e| {
         error!("Failed to create image: {}", e);
         errors::UserError::InternalError
     })?;
 
-    let q_params = parse_image_q_params(&req)?;
-
     if q_params.should_post_process() {
         png_bytes =
             post_processing::process_from_params(&q_params, &mut png_bytes).map_err(|e| {
@@ -133,12 +222,48 @@ async fn get_image(
         .body(png_bytes))
 }
 
+// Read a TOML, or JSON if the file name ends in .json, location catalog. On
+// failure, random images fall back to the built-in catalog
+fn load_catalog(path: &str) -> Option<Arc<Catalog>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| {
+            error!(
+                "Failed to read catalog {} - falling back to built-in - {}",
+                path, e
+            );
+        })
+        .ok()?;
+
+    let catalog = match Path::new(path).extension() {
+        Some(extension) if extension == "json" => Catalog::from_json(&contents),
+        _ => Catalog::from_toml(&contents),
+    }
+    .map_err(|e| {
+        error!(
+            "Failed to parse catalog {} - falling back to built-in - {}",
+            path, e
+        );
+    })
+    .ok()?;
+
+    info!(
+        "Loaded {} catalog locations from {}",
+        catalog.locations.len(),
+        path
+    );
+
+    Some(Arc::new(catalog))
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let env = Env::default();
 
     env_logger::init_from_env(env);
-    let args = server_config::ServerConfig::load_from_env();
+    let mut args = server_config::ServerConfig::load_from_env();
+    if let Some(catalog_path) = &args.catalog_path {
+        args.rand_options.catalog = load_catalog(catalog_path);
+    }
 
     let server_port = args.server_port;
     let server_addr = args.server_addr.to_owned();
@@ -166,10 +291,14 @@ async fn main() -> std::io::Result<()> {
 
         App::new()
             .wrap(cors)
+            .app_data(web::Data::new(args.rand_options))
+            .app_data(web::Data::new(args.output_limits))
             .service(get_random_direct)
             .service(get_random_from_worker_failover)
             .service(get_image_direct)
             .service(get_image_from_worker_failover)
+            .service(get_identity_image_direct)
+            .service(get_identity_image_from_worker_failover)
     })
     .bind((server_addr, server_port))?
     .run()
@@ -177,3 +306,67 @@ async fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    // The worker decodes identities itself, with `identity_from_encoded_path`
+    #[actix_web::test]
+    async fn identity_is_percent_decoded() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RandOptions::default()))
+                .app_data(web::Data::new(OutputLimits::default()))
+                .service(get_random_direct)
+                .service(get_identity_image_direct),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/id/j%C3%B6rg%20s.png?s=16")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+
+        let segment = "j%C3%B6rg%20s.png";
+        let mut img_params =
+            ImageParams::from_identity(&image_params::identity_from_encoded_path(segment).unwrap());
+        img_params.bounds = (16, 16);
+
+        assert_eq!(body, mandelbrot::create_png(&img_params).unwrap());
+    }
+
+    #[actix_web::test]
+    async fn sizes_are_within_configured_limits() {
+        let output_limits = OutputLimits {
+            min_dimension: 16,
+            max_dimension: 32,
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(RandOptions::default()))
+                .app_data(web::Data::new(output_limits))
+                .service(get_random_direct)
+                .service(get_identity_image_direct),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/id/jane.png?s=64")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Identities are 300x300, clamped to the configured limits
+        let req = test::TestRequest::get()
+            .uri("/api/v1/id/jane.png")
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let img_params = ImageParams::from_identity("jane");
+        assert_eq!(
+            body,
+            mandelbrot::create_png_within(&img_params, &output_limits).unwrap()
+        );
+    }
+}
@@ -1,4 +1,8 @@
 use log::error;
+use mandelatar_core::image_params::{
+    OutputLimits, RandOptions, DEFAULT_MAX_RAND_ATTEMPTS, DEFAULT_MIN_SCORE, DEFAULT_RAND_MAX_WORK,
+    MAX_OUTPUT_DIMENSION, MIN_OUTPUT_DIMENSION,
+};
 use std::env;
 
 const SERVER_PORT_DEFAULT: u16 = 8080;
@@ -9,6 +13,9 @@ pub struct ServerConfig {
     pub server_addr: String,
     pub server_port: u16,
     pub cors_origins: Vec<String>,
+    pub catalog_path: Option<String>,
+    pub rand_options: RandOptions,
+    pub output_limits: OutputLimits,
 }
 
 impl ServerConfig {
@@ -19,7 +26,7 @@ impl ServerConfig {
                 Err(_) => "127.0.0.1".to_string(),
             },
             server_port: match env::var("MANDELATAR_SERVER_PORT") {
-                Ok(port) => u16::from_str_radix(port.as_str(), 10).unwrap_or_else(|e| {
+                Ok(port) => port.parse::<u16>().unwrap_or_else(|e| {
                     error!(
                         "Failed to parse port - falling back to default {} - {}",
                         SERVER_PORT_DEFAULT, e
@@ -38,6 +45,63 @@ impl ServerConfig {
                     .map(|s| s.to_string())
                     .collect(),
             },
+            catalog_path: env::var("MANDELATAR_CATALOG_PATH").ok(),
+            rand_options: RandOptions {
+                min_score: match env::var("MANDELATAR_RANDOM_MIN_SCORE") {
+                    Ok(score) => score.parse::<f64>().unwrap_or_else(|e| {
+                        error!(
+                            "Failed to parse random min score - falling back to default {} - {}",
+                            DEFAULT_MIN_SCORE, e
+                        );
+                        DEFAULT_MIN_SCORE
+                    }),
+                    Err(_) => DEFAULT_MIN_SCORE,
+                },
+                max_attempts: match env::var("MANDELATAR_RANDOM_MAX_ATTEMPTS") {
+                    Ok(attempts) => attempts.parse::<usize>().unwrap_or_else(|e| {
+                        error!(
+                            "Failed to parse random max attempts - falling back to default {} - {}",
+                            DEFAULT_MAX_RAND_ATTEMPTS, e
+                        );
+                        DEFAULT_MAX_RAND_ATTEMPTS
+                    }),
+                    Err(_) => DEFAULT_MAX_RAND_ATTEMPTS,
+                },
+                max_work: match env::var("MANDELATAR_RANDOM_MAX_WORK") {
+                    Ok(work) => work.parse::<u64>().unwrap_or_else(|e| {
+                        error!(
+                            "Failed to parse random max work - falling back to default {} - {}",
+                            DEFAULT_RAND_MAX_WORK, e
+                        );
+                        DEFAULT_RAND_MAX_WORK
+                    }),
+                    Err(_) => DEFAULT_RAND_MAX_WORK,
+                },
+                // Loaded from `catalog_path` on startup
+                catalog: None,
+            },
+            output_limits: OutputLimits {
+                min_dimension: match env::var("MANDELATAR_MIN_OUTPUT_DIMENSION") {
+                    Ok(dimension) => dimension.parse::<usize>().unwrap_or_else(|e| {
+                        error!(
+                            "Failed to parse min output dimension - falling back to default {} - {}",
+                            MIN_OUTPUT_DIMENSION, e
+                        );
+                        MIN_OUTPUT_DIMENSION
+                    }),
+                    Err(_) => MIN_OUTPUT_DIMENSION,
+                },
+                max_dimension: match env::var("MANDELATAR_MAX_OUTPUT_DIMENSION") {
+                    Ok(dimension) => dimension.parse::<usize>().unwrap_or_else(|e| {
+                        error!(
+                            "Failed to parse max output dimension - falling back to default {} - {}",
+                            MAX_OUTPUT_DIMENSION, e
+                        );
+                        MAX_OUTPUT_DIMENSION
+                    }),
+                    Err(_) => MAX_OUTPUT_DIMENSION,
+                },
+            },
         }
     }
 }
@@ -0,0 +1,235 @@
+use num::Complex;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+use crate::errors;
+use crate::fractal::Formula;
+use crate::image_params::{ComplexDef, ImageTransformFlags};
+use crate::numeric;
+use crate::palette::{Palette, PaletteKind};
+
+/// The catalog compiled into the crate, sampled from unless another one is
+/// loaded.
+pub const DEFAULT_CATALOG_TOML: &str = include_str!("../assets/catalog.toml");
+
+/// Smallest half-width/height of a location's view. Much closer and the f64
+/// corners of the view can no longer tell its pixels apart.
+pub const MIN_LOCATION_HALF_EXTENT: f64 = 1.0e-12;
+/// Largest half-width/height of a location's view (the whole set).
+pub const MAX_LOCATION_HALF_EXTENT: f64 = 4.0;
+
+/// A hand-picked spot for `ImageParams::new_from_rand` to zoom in on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    /// What the spot is known as, for whoever is editing the catalog.
+    #[serde(default)]
+    pub name: String,
+    /// Formula of the set the spot is on.
+    #[serde(default)]
+    pub formula: Formula,
+    /// Center of the view.
+    #[serde(with = "ComplexDef")]
+    pub center: Complex<f64>,
+    /// Smallest and largest half-width/height of the view. Picked between
+    /// log-uniformly, so each order of magnitude of zoom is as likely.
+    pub half_extent: (f64, f64),
+    /// Palettes to pick from, or any of the built-in palettes if unset.
+    pub palettes: Option<Vec<PaletteKind>>,
+    /// Transforms that may each be applied, or the usual random ones if unset.
+    pub transforms: Option<Vec<ImageTransformFlags>>,
+    /// How likely the spot is to be picked, relative to the others.
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+impl Location {
+    /// Check the location can be rendered, as catalogs are loaded from files.
+    pub fn validate(&self) -> Result<(), errors::InvalidCatalog> {
+        let invalid = |message: String| errors::InvalidCatalog::Default {
+            message: format!("location \"{}\": {}", self.name, message),
+        };
+
+        self.formula
+            .validate()
+            .map_err(|e| invalid(e.to_string()))?;
+
+        if !self.center.re.is_finite() || !self.center.im.is_finite() {
+            return Err(invalid("center must be finite".to_string()));
+        }
+
+        let (min, max) = self.half_extent;
+        let extents = MIN_LOCATION_HALF_EXTENT..=MAX_LOCATION_HALF_EXTENT;
+        if !extents.contains(&min) || !extents.contains(&max) || min > max {
+            return Err(invalid(format!(
+                "half extents must be from {:e} to {}, smallest first",
+                MIN_LOCATION_HALF_EXTENT, MAX_LOCATION_HALF_EXTENT
+            )));
+        }
+
+        if let Some(palettes) = &self.palettes {
+            if palettes.is_empty() {
+                return Err(invalid(
+                    "palettes must list at least one palette".to_string(),
+                ));
+            }
+
+            for kind in palettes {
+                let palette = Palette {
+                    kind: kind.clone(),
+                    cycle_length: 1,
+                    offset: 0,
+                };
+                palette.validate().map_err(|e| invalid(e.to_string()))?;
+            }
+        }
+
+        if !self.weight.is_finite() || self.weight < 0.0 {
+            return Err(invalid(
+                "weight must be a finite number, at least 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// A random half-width/height for a view of the location.
+    pub fn rand_half_extent(&self, rng: &mut impl Rng) -> f64 {
+        let (min, max) = self.half_extent;
+        if min >= max {
+            return min;
+        }
+
+        numeric::exp(rng.gen_range(numeric::ln(min)..=numeric::ln(max)))
+    }
+}
+
+/// A list of hand-picked locations, loaded from TOML like
+/// `assets/catalog.toml` or the equivalent JSON.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Catalog {
+    #[serde(default)]
+    pub locations: Vec<Location>,
+}
+
+impl Catalog {
+    /// The catalog in `DEFAULT_CATALOG_TOML`.
+    pub fn builtin() -> &'static Catalog {
+        static BUILTIN: OnceLock<Catalog> = OnceLock::new();
+
+        BUILTIN.get_or_init(|| {
+            Catalog::from_toml(DEFAULT_CATALOG_TOML).expect("built-in catalog should be valid")
+        })
+    }
+
+    pub fn from_toml(catalog: &str) -> Result<Self, errors::InvalidCatalog> {
+        let catalog: Self =
+            toml::from_str(catalog).map_err(|e| errors::InvalidCatalog::Default {
+                message: e.to_string(),
+            })?;
+        catalog.validate()?;
+
+        Ok(catalog)
+    }
+
+    pub fn from_json(catalog: &str) -> Result<Self, errors::InvalidCatalog> {
+        let catalog: Self =
+            serde_json::from_str(catalog).map_err(|e| errors::InvalidCatalog::Default {
+                message: e.to_string(),
+            })?;
+        catalog.validate()?;
+
+        Ok(catalog)
+    }
+
+    pub fn validate(&self) -> Result<(), errors::InvalidCatalog> {
+        self.locations.iter().try_for_each(Location::validate)
+    }
+
+    /// A random location, picked by weight, or `None` if the catalog has no
+    /// locations with any weight.
+    pub fn rand_location(&self, rng: &mut impl Rng) -> Option<&Location> {
+        let weights = WeightedIndex::new(self.locations.iter().map(|location| location.weight));
+
+        weights
+            .ok()
+            .map(|weights| &self.locations[weights.sample(rng)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    // A catalog of one location, with `fields` in place of the defaults
+    fn location(fields: &str) -> String {
+        format!(
+            "[[locations]]\nname = \"Test\"\ncenter = {{ re = -0.75, im = 0.1 }}\n{}\n",
+            fields
+        )
+    }
+
+    #[test]
+    fn builtin_catalog_parses() {
+        let catalog = Catalog::builtin();
+
+        assert!(!catalog.locations.is_empty());
+        assert!(catalog.validate().is_ok());
+        assert!(catalog
+            .locations
+            .iter()
+            .all(|location| location.weight > 0.0));
+    }
+
+    #[test]
+    fn invalid_locations_are_rejected() {
+        assert!(Catalog::from_toml(&location("half_extent = [1.0e-4, 1.0e-2]")).is_ok());
+
+        for fields in [
+            // Bad extents
+            "half_extent = [1.0e-2, 1.0e-4]",
+            "half_extent = [1.0e-20, 1.0e-2]",
+            "half_extent = [1.0e-4, 10.0]",
+            "half_extent = [0.0, 1.0e-2]",
+            // Bad weights
+            "half_extent = [1.0e-4, 1.0e-2]\nweight = -1.0",
+            "half_extent = [1.0e-4, 1.0e-2]\nweight = nan",
+            "half_extent = [1.0e-4, 1.0e-2]\nweight = inf",
+            // An empty palette list
+            "half_extent = [1.0e-4, 1.0e-2]\npalettes = []",
+        ] {
+            assert!(Catalog::from_toml(&location(fields)).is_err(), "{}", fields);
+        }
+    }
+
+    #[test]
+    fn weightless_catalogs_have_no_locations_to_pick() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+
+        let weightless = Catalog::from_toml(&format!(
+            "{}{}",
+            location("half_extent = [1.0e-4, 1.0e-2]\nweight = 0.0"),
+            location("half_extent = [1.0e-3, 1.0e-2]\nweight = 0.0"),
+        ))
+        .unwrap();
+        assert_eq!(weightless.rand_location(&mut rng), None);
+        assert_eq!(Catalog::default().rand_location(&mut rng), None);
+
+        // Only the weighted location is ever picked
+        let mut weighted = weightless.clone();
+        weighted.locations[1].weight = 1.0;
+        for _ in 0..16 {
+            assert_eq!(
+                weighted.rand_location(&mut rng),
+                Some(&weighted.locations[1])
+            );
+        }
+    }
+}
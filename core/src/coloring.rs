@@ -0,0 +1,986 @@
+use image::Rgba;
+use num::Complex;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::{LN_2, PI, TAU};
+
+use crate::errors;
+use crate::expression::Program;
+use crate::image_params::{ComplexDef, ImageParams, MAX_ITERATION_LIMIT};
+use crate::numeric;
+use crate::palette::{Gradient, Palette};
+
+/// Squared escape radius of the original escape-time coloring.
+pub const ESCAPE_TIME_BAILOUT_SQR: f64 = 4.0;
+
+/// Squared escape radius used for smooth coloring. A larger radius makes the
+/// normalized iteration count continuous across iteration bands.
+pub const SMOOTH_BAILOUT_SQR: f64 = 256.0 * 256.0;
+
+// Number of (smoothed) iterations it takes to cycle through the color wheel
+const SMOOTH_COLOR_PERIOD: f64 = 48.0;
+
+/// Largest width of the lines drawn by `ColoringMode::Distance`, in pixels.
+pub const MAX_LINE_WIDTH: f32 = 16.0;
+/// Largest falloff distance of the glow around `ColoringMode::Distance` lines,
+/// in pixels.
+pub const MAX_GLOW: f32 = 64.0;
+
+// Color of the set's interior with `InteriorColoring::Fixed`
+const INTERIOR_COLOR: Rgba<u8> = Rgba([10, 10, 25, 255]);
+// Background of `ColoringMode::Distance`, the same as the default interior
+const LINE_ART_BACKGROUND: Rgba<u8> = INTERIOR_COLOR;
+// Brightness of the glow right at the edge of a line, relative to the line
+const GLOW_INTENSITY: f64 = 0.6;
+
+// Fraction of the palette between the colors of consecutive periods with
+// `InteriorColoring::Period`: the golden ratio, which keeps nearby periods far
+// apart on the palette
+const PERIOD_COLOR_STEP: f64 = 0.618_033_988_749_894_9;
+// Largest final |z| shaded by `InteriorColoring::Magnitude`, at the end of the
+// palette
+const MAX_INTERIOR_MAGNITUDE: f64 = 2.0;
+
+/// Largest distance of an `OrbitTrap` from the origin. Orbits never get much
+/// further than the bailout radius, so traps past it would be empty.
+pub const MAX_TRAP_EXTENT: f64 = 4.0;
+/// Largest number of stripes per turn of `ColoringMode::StripeAverage`.
+pub const MAX_STRIPE_DENSITY: f32 = 16.0;
+
+// Iterations (as colored by the palette or `smooth_color`) spanned by the
+// range of an orbit average, and by each factor of e closer an orbit gets to a
+// trap
+const ORBIT_AVERAGE_SPAN: f64 = SMOOTH_COLOR_PERIOD;
+const ORBIT_TRAP_SPAN: f64 = 12.0;
+
+/// A shape that orbits are measured against by `ColoringMode::OrbitTrap`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OrbitTrap {
+    /// A single point.
+    Point {
+        #[serde(with = "ComplexDef")]
+        center: Complex<f64>,
+    },
+    /// A line through `point`, at `angle` radians from the real axis.
+    Line {
+        #[serde(with = "ComplexDef")]
+        point: Complex<f64>,
+        angle: f64,
+    },
+    /// Two perpendicular lines crossing at `center`, the first at `angle`
+    /// radians from the real axis.
+    Cross {
+        #[serde(with = "ComplexDef")]
+        center: Complex<f64>,
+        angle: f64,
+    },
+}
+
+impl OrbitTrap {
+    /// Check the trap can be rendered, as it's read from user-supplied tokens.
+    pub fn validate(&self) -> Result<(), errors::InvalidImageParams> {
+        let (center, angle) = match *self {
+            OrbitTrap::Point { center } => (center, 0.0),
+            OrbitTrap::Line { point, angle } => (point, angle),
+            OrbitTrap::Cross { center, angle } => (center, angle),
+        };
+
+        // Also false for NaNs
+        let in_range = numeric::norm(center) <= MAX_TRAP_EXTENT;
+        if !in_range || !angle.is_finite() {
+            return Err(errors::InvalidImageParams::Default {
+                message: format!(
+                    "orbit traps must be within {} of the origin, at a finite angle",
+                    MAX_TRAP_EXTENT
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The trap with its rotation worked out, to measure orbits against.
+    pub fn prepare(&self) -> PreparedTrap {
+        let angle = match *self {
+            OrbitTrap::Point { .. } => 0.0,
+            OrbitTrap::Line { angle, .. } | OrbitTrap::Cross { angle, .. } => angle,
+        };
+
+        PreparedTrap {
+            trap: *self,
+            rotation: numeric::from_polar(1.0, -angle),
+        }
+    }
+}
+
+/// An `OrbitTrap` set up once per image, so measuring each step of an orbit
+/// against it takes no trigonometry or square roots.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PreparedTrap {
+    trap: OrbitTrap,
+    /// Rotation taking the trap's lines onto the axes.
+    rotation: Complex<f64>,
+}
+
+impl PreparedTrap {
+    /// Squared distance from `z` to the trap.
+    pub fn distance_sqr(&self, z: Complex<f64>) -> f64 {
+        // `z` relative to the trap, rotated so its lines are along the axes
+        let local = |origin: Complex<f64>| (z - origin) * self.rotation;
+
+        match self.trap {
+            OrbitTrap::Point { center } => (z - center).norm_sqr(),
+            OrbitTrap::Line { point, .. } => local(point).im.powi(2),
+            OrbitTrap::Cross { center, .. } => {
+                let local = local(center);
+                local.re.powi(2).min(local.im.powi(2))
+            }
+        }
+    }
+}
+
+/// How escaped points are mapped to colors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ColoringMode {
+    /// The original coloring: each of `rgb_consts` modulo the integer escape
+    /// count. Produces hard bands, but is kept so existing tokens don't change.
+    #[default]
+    EscapeTime,
+    /// Normalized (continuous) iteration count, giving smooth gradients.
+    Smooth,
+    /// Line art: the boundary of the set drawn as lines `line_width` pixels
+    /// wide on a plain background, placed by estimating each point's distance
+    /// to the set. Lines are the same width at any zoom or output size.
+    ///
+    /// With a `glow` above 0, the lines fade out into the background over
+    /// about that many pixels.
+    Distance { line_width: f32, glow: f32 },
+    /// How close orbits come to `OrbitTrap`.
+    OrbitTrap(OrbitTrap),
+    /// Stripe average: the average over the orbit of `sin(density * arg(z))`,
+    /// giving stripes that follow the set's filaments.
+    StripeAverage { density: f32 },
+    /// Curvature average: the average over the orbit of how sharply it turns.
+    CurvatureAverage,
+    /// Histogram-equalized coloring: normalized iteration counts, spread over
+    /// the palette by how many points of the image escaped at each count, so
+    /// every image uses the whole palette once whatever its zoom.
+    Histogram,
+}
+
+impl ColoringMode {
+    /// Check the mode can be rendered, as it's read from user-supplied tokens.
+    pub fn validate(&self) -> Result<(), errors::InvalidImageParams> {
+        if let ColoringMode::Distance { line_width, glow } = *self {
+            if !(line_width > 0.0 && line_width <= MAX_LINE_WIDTH) {
+                return Err(errors::InvalidImageParams::Default {
+                    message: format!("line width must be above 0 and at most {}", MAX_LINE_WIDTH),
+                });
+            }
+
+            if !(0.0..=MAX_GLOW).contains(&glow) {
+                return Err(errors::InvalidImageParams::Default {
+                    message: format!("glow must be from 0 to {}", MAX_GLOW),
+                });
+            }
+        }
+
+        if let ColoringMode::OrbitTrap(trap) = self {
+            trap.validate()?;
+        }
+
+        if let ColoringMode::StripeAverage { density } = *self {
+            if !(1.0..=MAX_STRIPE_DENSITY).contains(&density) {
+                return Err(errors::InvalidImageParams::Default {
+                    message: format!("stripe density must be from 1 to {}", MAX_STRIPE_DENSITY),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Squared escape radius the escape-time loop should use for this mode.
+    pub fn bailout_sqr(&self) -> f64 {
+        match self {
+            ColoringMode::EscapeTime => ESCAPE_TIME_BAILOUT_SQR,
+            _ => SMOOTH_BAILOUT_SQR,
+        }
+    }
+
+    /// Whether the escape-time loop needs to carry the derivative of each
+    /// orbit for this mode.
+    pub fn needs_derivative(&self) -> bool {
+        matches!(self, ColoringMode::Distance { .. })
+    }
+
+    /// Whether colors depend on the escape counts of the whole image, as
+    /// gathered by a first pass into an `IterationHistogram`.
+    pub fn needs_histogram(&self) -> bool {
+        *self == ColoringMode::Histogram
+    }
+
+    /// What the escape-time loop needs to gather `OrbitStats` of for this
+    /// mode, if anything.
+    pub fn orbit_coloring(&self) -> Option<OrbitColoring> {
+        match *self {
+            ColoringMode::OrbitTrap(trap) => Some(OrbitColoring::Trap(trap.prepare())),
+            ColoringMode::StripeAverage { density } => Some(OrbitColoring::StripeAverage {
+                density: density as f64,
+            }),
+            ColoringMode::CurvatureAverage => Some(OrbitColoring::CurvatureAverage),
+            _ => None,
+        }
+    }
+}
+
+/// How points in the set, whose orbits never escaped, are colored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum InteriorColoring {
+    /// The original dark navy.
+    #[default]
+    Fixed,
+    /// A single color, `position` of the way through the palette (from 0 to
+    /// 1), or through the `rgb_consts` color wheel without one.
+    Solid { position: f32 },
+    /// Shaded through the palette by the magnitude of the last value of the
+    /// orbit.
+    Magnitude,
+    /// A color for each period of the cycle the orbit settles into, which is
+    /// the same throughout each bulb of the set. Orbits that haven't settled
+    /// by the iteration limit get the `Fixed` color.
+    Period,
+    /// Fully transparent.
+    Transparent,
+}
+
+impl InteriorColoring {
+    /// Check the mode can be rendered, as it's read from user-supplied tokens.
+    pub fn validate(&self) -> Result<(), errors::InvalidImageParams> {
+        if let InteriorColoring::Solid { position } = *self {
+            if !(0.0..=1.0).contains(&position) {
+                return Err(errors::InvalidImageParams::Default {
+                    message: "solid interior position must be from 0 to 1".to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the escape-time loop needs to keep the orbits of the points
+    /// that don't escape for this mode. Deep zooms can't, and color them
+    /// `Fixed`.
+    pub fn needs_orbit(&self) -> bool {
+        matches!(self, InteriorColoring::Magnitude | InteriorColoring::Period)
+    }
+}
+
+/// Largest number of iterations a `TransparentBand` fades in over.
+pub const MAX_BAND_SOFTNESS: f32 = 64.0;
+
+/// A band of (normalized) iteration counts whose escaped points are made
+/// transparent, fading back in over `softness` iterations on either side.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TransparentBand {
+    pub start: f32,
+    pub end: f32,
+    pub softness: f32,
+}
+
+impl TransparentBand {
+    /// Check the band can be rendered, as it's read from user-supplied tokens.
+    pub fn validate(&self) -> Result<(), errors::InvalidImageParams> {
+        let max_count = MAX_ITERATION_LIMIT as f32;
+        if !(0.0 <= self.start && self.start <= self.end && self.end <= max_count) {
+            return Err(errors::InvalidImageParams::Default {
+                message: format!(
+                    "transparent band must be within 0 to {} iterations",
+                    MAX_ITERATION_LIMIT
+                ),
+            });
+        }
+
+        if !(0.0..=MAX_BAND_SOFTNESS).contains(&self.softness) {
+            return Err(errors::InvalidImageParams::Default {
+                message: format!(
+                    "transparent band softness must be from 0 to {}",
+                    MAX_BAND_SOFTNESS
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Opacity, from 0 to 1, of a point with the normalized iteration count
+    /// `mu`.
+    pub fn opacity(&self, mu: f64) -> f64 {
+        let outside = (self.start as f64 - mu).max(mu - self.end as f64);
+
+        if self.softness > 0.0 {
+            (outside / self.softness as f64).clamp(0.0, 1.0)
+        } else if outside > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A coloring mode that looks at the whole orbit rather than just where it
+/// escaped, set up once per image for gathering `OrbitStats`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OrbitColoring {
+    Trap(PreparedTrap),
+    StripeAverage { density: f64 },
+    CurvatureAverage,
+}
+
+/// Statistics of an orbit, gathered while iterating it, for `OrbitColoring`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OrbitStats {
+    /// Smallest squared distance from the orbit to the `OrbitTrap`.
+    pub trap_distance_sqr: f64,
+    /// Sum of the terms of a stripe or curvature average.
+    pub sum: f64,
+    /// The last term added to `sum`.
+    pub last: f64,
+    /// Number of terms in `sum`.
+    pub terms: usize,
+    /// The last two values recorded, newest first.
+    previous: [Option<Complex<f64>>; 2],
+}
+
+impl Default for OrbitStats {
+    fn default() -> Self {
+        Self {
+            trap_distance_sqr: f64::INFINITY,
+            sum: 0.0,
+            last: 0.0,
+            terms: 0,
+            previous: [None; 2],
+        }
+    }
+}
+
+impl OrbitStats {
+    /// Record the next value `z` of the orbit, for coloring with `coloring`.
+    pub fn record(&mut self, coloring: &OrbitColoring, z: Complex<f64>) {
+        let term = match (coloring, self.previous) {
+            (OrbitColoring::Trap(trap), _) => {
+                self.trap_distance_sqr = self.trap_distance_sqr.min(trap.distance_sqr(z));
+                None
+            }
+            (OrbitColoring::StripeAverage { density }, _) => {
+                Some(0.5 * numeric::sin(density * numeric::arg(z)) + 0.5)
+            }
+            (OrbitColoring::CurvatureAverage, [Some(z1), Some(z2)]) if z1 != z2 => {
+                Some(numeric::arg((z - z1) / (z1 - z2)).abs() / PI)
+            }
+            _ => None,
+        };
+
+        if let Some(term) = term.filter(|term| term.is_finite()) {
+            self.sum += term;
+            self.last = term;
+            self.terms += 1;
+        }
+
+        self.previous = [Some(z), self.previous[0]];
+    }
+
+    /// The average of the terms in `sum`, interpolated towards the average
+    /// without the last term by `1 - weight`, so averages blend smoothly
+    /// across iteration bands.
+    pub fn average(&self, weight: f64) -> f64 {
+        if self.terms == 0 {
+            return 0.0;
+        }
+
+        let average = self.sum / self.terms as f64;
+        let previous = if self.terms > 1 {
+            (self.sum - self.last) / (self.terms - 1) as f64
+        } else {
+            average
+        };
+
+        previous + weight * (average - previous)
+    }
+}
+
+/// The distribution of escape counts over an image, for histogram
+/// equalization.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IterationHistogram {
+    /// Fraction of escaped points with a count below each index.
+    cumulative: Vec<f64>,
+}
+
+impl IterationHistogram {
+    /// The histogram of `counts`, the escape counts of every escaped point
+    /// sampled, each below `limit`.
+    pub fn new(limit: usize, counts: impl Iterator<Item = usize>) -> Self {
+        let mut bins = vec![0usize; limit + 1];
+        for count in counts {
+            bins[count.min(limit)] += 1;
+        }
+
+        let total = bins.iter().sum::<usize>().max(1) as f64;
+        let mut below = 0;
+        let mut cumulative = Vec::with_capacity(bins.len() + 1);
+        cumulative.push(0.0);
+        for bin in bins {
+            below += bin;
+            cumulative.push(below as f64 / total);
+        }
+
+        Self { cumulative }
+    }
+
+    /// The fraction of escaped points with a lower normalized iteration count
+    /// than `mu`, interpolated between escape counts.
+    pub fn equalize(&self, mu: f64) -> f64 {
+        let last = self.cumulative.len() - 1;
+        let mu = mu.clamp(0.0, last as f64);
+        let below = (mu.floor() as usize).min(last - 1);
+        let (lower, upper) = (self.cumulative[below], self.cumulative[below + 1]);
+
+        lower + (mu - below as f64) * (upper - lower)
+    }
+}
+
+/// Color for a point that escaped after `count` iterations, using the original
+/// modulo coloring.
+pub fn escape_time_color(count: usize, (r, g, b): (u8, u8, u8)) -> Rgba<u8> {
+    // Wrap counts past 255 back around to 1, rather than to 0
+    let count = ((count.max(1) - 1) % 255 + 1) as u8;
+
+    Rgba([r % count, g % count, b % count, 255])
+}
+
+/// The normalized iteration count of a point that escaped after `count`
+/// iterations, with `z` being the first value of the orbit outside of the
+/// bailout radius.
+///
+/// `ln_degree` is the natural log of the degree of the formula iterated, which
+/// is `LN_2` for `z^2 + c`.
+pub fn smooth_iteration_count(count: usize, z: Complex<f64>, ln_degree: f64) -> f64 {
+    // log_d(log_d|z|), with log|z| = log(|z|^2) / 2
+    let log_zn = numeric::ln(z.norm_sqr()) / 2.0;
+    let nu = numeric::ln(log_zn / ln_degree) / ln_degree;
+
+    (count as f64 + 1.0 - nu).max(0.0)
+}
+
+/// Color for a normalized iteration count `mu`. Each channel follows a cosine
+/// wave through the iterations, phase-shifted by its `rgb_consts` value.
+pub fn smooth_color(mu: f64, (r, g, b): (u8, u8, u8)) -> Rgba<u8> {
+    let t = mu / SMOOTH_COLOR_PERIOD;
+    let channel =
+        |phase: u8| (127.5 * (1.0 - numeric::cos(TAU * (t + phase as f64 / 255.0)))).round() as u8;
+
+    Rgba([channel(r), channel(g), channel(b), 255])
+}
+
+/// Estimated distance from a point to the set, given the first value `z` of
+/// its orbit outside of the bailout radius and the derivative of `z` with
+/// respect to the point.
+///
+/// This is `G / |G'|`, for the potential `G` of the point - within a small
+/// factor of the true distance.
+pub fn distance_estimate(z: Complex<f64>, derivative: Complex<f64>) -> f64 {
+    let z_norm = numeric::norm(z);
+    z_norm * numeric::ln(z_norm) / numeric::norm(derivative)
+}
+
+/// Color of a point `distance` pixels from the set, with lines of
+/// `line_color`, for `ColoringMode::Distance`.
+pub fn line_art_color(distance: f64, line_width: f32, glow: f32, line_color: Rgba<u8>) -> Rgba<u8> {
+    // Cover a line `line_width` wide, with a pixel of antialiasing at its edge
+    let line = (line_width as f64 + 0.5 - distance).clamp(0.0, 1.0);
+    let glow = if glow > 0.0 {
+        GLOW_INTENSITY * numeric::exp(-(distance - line_width as f64).max(0.0) / glow as f64)
+    } else {
+        0.0
+    };
+    let intensity = line.max(glow);
+
+    let mut color = LINE_ART_BACKGROUND;
+    for (channel, line_channel) in color.0.iter_mut().zip(line_color.0).take(3) {
+        *channel =
+            (*channel as f64 + intensity * (line_channel as f64 - *channel as f64)).round() as u8;
+    }
+
+    color
+}
+
+/// Everything needed to color the escaped points of an image, set up once per
+/// image from its `ImageParams`.
+pub struct ColorScheme {
+    mode: ColoringMode,
+    interior: InteriorColoring,
+    rgb_consts: (u8, u8, u8),
+    palette: Option<(Palette, Gradient)>,
+    ln_degree: f64,
+    /// Log of the bailout radius, for blending orbit averages.
+    ln_bailout: f64,
+    /// Size of a pixel on the complex plane, for distance estimation.
+    pixel_size: f64,
+    /// Escape counts of the image, for `ColoringMode::Histogram`.
+    histogram: Option<IterationHistogram>,
+    transparent_band: Option<TransparentBand>,
+}
+
+impl ColorScheme {
+    /// Set up the colors of an image, whose pixels are `pixel_size` apart on
+    /// the complex plane.
+    pub fn from_params(img_params: &ImageParams, pixel_size: f64) -> Self {
+        Self {
+            mode: img_params.coloring,
+            interior: img_params.interior,
+            rgb_consts: img_params.rgb_consts,
+            palette: img_params
+                .palette
+                .as_ref()
+                .map(|palette| (palette.clone(), palette.kind.gradient())),
+            ln_degree: match &img_params.custom_formula {
+                Some(source) => Program::compile(source)
+                    .map(|program| program.ln_degree())
+                    .unwrap_or(LN_2),
+                None => img_params.formula.ln_degree(),
+            },
+            ln_bailout: numeric::ln(img_params.get_bailout_sqr()) / 2.0,
+            pixel_size,
+            histogram: None,
+            transparent_band: img_params.transparent_band,
+        }
+    }
+
+    /// Whether `set_histogram` must be called before coloring any points.
+    pub fn needs_histogram(&self) -> bool {
+        self.mode.needs_histogram()
+    }
+
+    /// Set the escape counts of the image being colored, gathered in a first
+    /// pass over it.
+    pub fn set_histogram(&mut self, histogram: IterationHistogram) {
+        self.histogram = Some(histogram);
+    }
+
+    /// Whether colors depend only on escape counts (and not on where orbits
+    /// escaped to).
+    pub fn depends_only_on_count(&self) -> bool {
+        self.mode == ColoringMode::EscapeTime && self.transparent_band.is_none()
+    }
+
+    /// Color for a point that escaped after `count` iterations, with `z` being
+    /// the first value of the orbit outside of the bailout radius. Some modes
+    /// also need the `derivative` of `z`, or the `orbit` stats.
+    ///
+    /// Without a palette, this falls back to coloring with `rgb_consts`.
+    pub fn exterior_color(
+        &self,
+        count: usize,
+        z: Complex<f64>,
+        derivative: Option<Complex<f64>>,
+        orbit: Option<&OrbitStats>,
+    ) -> Rgba<u8> {
+        let iterations = match (self.mode, orbit) {
+            (ColoringMode::EscapeTime, _) => count as f64,
+            (ColoringMode::OrbitTrap(_), Some(orbit)) => {
+                -numeric::ln(orbit.trap_distance_sqr.sqrt()) * ORBIT_TRAP_SPAN
+            }
+            (ColoringMode::StripeAverage { .. } | ColoringMode::CurvatureAverage, Some(orbit)) => {
+                // How far past the bailout radius the orbit jumped, which is
+                // 0 just after escaping and 1 just before escaping a step
+                // sooner
+                let overshoot = (numeric::ln(numeric::ln(numeric::norm(z)) / self.ln_bailout)
+                    / self.ln_degree)
+                    .clamp(0.0, 1.0);
+
+                orbit.average(1.0 - overshoot) * ORBIT_AVERAGE_SPAN
+            }
+            (ColoringMode::Histogram, _) => {
+                let mu = smooth_iteration_count(count, z, self.ln_degree);
+                match &self.histogram {
+                    Some(histogram) => histogram.equalize(mu) * self.palette_span(),
+                    None => mu,
+                }
+            }
+            _ => smooth_iteration_count(count, z, self.ln_degree),
+        };
+
+        let color = match (&self.palette, self.mode) {
+            (None, ColoringMode::EscapeTime) => escape_time_color(count, self.rgb_consts),
+            (None, ColoringMode::Distance { .. }) => {
+                let (r, g, b) = self.rgb_consts;
+                Rgba([r, g, b, 255])
+            }
+            _ => self.smooth_palette_color(iterations),
+        };
+
+        let mut color = match (self.mode, derivative) {
+            (ColoringMode::Distance { line_width, glow }, Some(derivative)) => {
+                let distance = distance_estimate(z, derivative) / self.pixel_size;
+                line_art_color(distance, line_width, glow, color)
+            }
+            _ => color,
+        };
+
+        if let Some(band) = &self.transparent_band {
+            let opacity = band.opacity(smooth_iteration_count(count, z, self.ln_degree));
+            color.0[3] = (color.0[3] as f64 * opacity).round() as u8;
+        }
+
+        color
+    }
+
+    /// Color for a point in the set, whose orbit was last at `z`, having
+    /// settled into a cycle of `period`, when they're known.
+    pub fn interior_color(&self, z: Option<Complex<f64>>, period: Option<usize>) -> Rgba<u8> {
+        match (self.interior, z, period) {
+            (InteriorColoring::Solid { position }, _, _) => {
+                self.smooth_palette_color(position as f64 * self.palette_span())
+            }
+            (InteriorColoring::Magnitude, Some(z), _) => {
+                let shade = (numeric::norm(z) / MAX_INTERIOR_MAGNITUDE).min(1.0);
+                self.smooth_palette_color(shade * self.palette_span())
+            }
+            (InteriorColoring::Period, _, Some(period)) => {
+                let position = ((period - 1) as f64 * PERIOD_COLOR_STEP).fract();
+                self.smooth_palette_color(position * self.palette_span())
+            }
+            (InteriorColoring::Transparent, _, _) => Rgba([0, 0, 0, 0]),
+            _ => INTERIOR_COLOR,
+        }
+    }
+
+    /// Number of iterations it takes to go once through the palette, or
+    /// through the `rgb_consts` color wheel without one.
+    fn palette_span(&self) -> f64 {
+        match &self.palette {
+            Some((palette, _)) => palette.cycle_length as f64,
+            None => SMOOTH_COLOR_PERIOD,
+        }
+    }
+
+    /// Color for a (normalized) iteration count from the palette, or from the
+    /// `rgb_consts` color wheel without one.
+    fn smooth_palette_color(&self, iterations: f64) -> Rgba<u8> {
+        match &self.palette {
+            Some((palette, gradient)) => gradient.sample(palette.position(iterations)),
+            None => smooth_color(iterations, self.rgb_consts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mandelbrot::tests::whole_set;
+    use crate::palette::PaletteKind;
+
+    // The first value outside of the smooth bailout radius of the orbit of
+    // `c`, with its derivative and escape count
+    fn escape(c: Complex<f64>) -> (usize, Complex<f64>, Complex<f64>) {
+        let mut z = Complex::new(0.0, 0.0);
+        let mut derivative = Complex::new(0.0, 0.0);
+
+        for count in 0..1000 {
+            if z.norm_sqr() > SMOOTH_BAILOUT_SQR {
+                return (count, z, derivative);
+            }
+            derivative = 2.0 * z * derivative + 1.0;
+            z = z * z + c;
+        }
+        panic!("{} doesn't escape", c)
+    }
+
+    fn scheme(coloring: ColoringMode, interior: InteriorColoring) -> ColorScheme {
+        let mut img_params = whole_set((64, 64));
+        img_params.coloring = coloring;
+        img_params.interior = interior;
+        img_params.palette = Some(Palette {
+            kind: PaletteKind::Grayscale,
+            cycle_length: 16,
+            offset: 0,
+        });
+
+        ColorScheme::from_params(&img_params, 0.01)
+    }
+
+    #[test]
+    fn smooth_counts_are_continuous_across_bands() {
+        // A step further along the orbit, the count goes up by one and the
+        // fractional part makes up for it
+        let z = Complex::new(300.0, 400.0);
+        let mu = smooth_iteration_count(5, z, LN_2);
+        assert!((smooth_iteration_count(6, z * z, LN_2) - mu).abs() < 1.0e-9);
+
+        // Neighboring points either side of a band change color by little
+        let (count_a, z_a, _) = escape(Complex::new(0.3, 0.0));
+        let (count_b, z_b, _) = escape(Complex::new(0.3001, 0.0));
+        let (mu_a, mu_b) = (
+            smooth_iteration_count(count_a, z_a, LN_2),
+            smooth_iteration_count(count_b, z_b, LN_2),
+        );
+        assert!((mu_a - mu_b).abs() < 0.5, "{} and {}", mu_a, mu_b);
+    }
+
+    #[test]
+    fn distance_estimates_are_within_a_factor_of_the_distance() {
+        // Points on the real axis past the cusp at 0.25
+        for (c, distance) in [(0.5, 0.25), (1.0, 0.75), (2.0, 1.75)] {
+            let (_, z, derivative) = escape(Complex::new(c, 0.0));
+            let estimate = distance_estimate(z, derivative);
+
+            assert!(
+                estimate / 4.0 <= distance && distance <= 4.0 * estimate,
+                "{} estimated at {}",
+                distance,
+                estimate
+            );
+        }
+
+        let line = Rgba([200, 220, 240, 255]);
+        assert_eq!(line_art_color(0.0, 1.0, 0.0, line), line);
+        assert_eq!(line_art_color(10.0, 1.0, 0.0, line), LINE_ART_BACKGROUND);
+        // Glowing fades out with the distance from the line
+        let near = line_art_color(3.0, 1.0, 4.0, line);
+        let far = line_art_color(30.0, 1.0, 4.0, line);
+        assert!(near.0[0] > far.0[0] && far.0[0] >= LINE_ART_BACKGROUND.0[0]);
+    }
+
+    #[test]
+    fn orbit_traps_measure_distance_to_their_shape() {
+        let z = Complex::new(5.0, 3.0);
+        let origin = Complex::new(0.0, 0.0);
+
+        let point = OrbitTrap::Point { center: origin }.prepare();
+        assert_eq!(point.distance_sqr(z), 34.0);
+
+        let real_axis = OrbitTrap::Line {
+            point: origin,
+            angle: 0.0,
+        };
+        assert_eq!(real_axis.prepare().distance_sqr(z), 9.0);
+
+        let imaginary_axis = OrbitTrap::Line {
+            point: origin,
+            angle: std::f64::consts::FRAC_PI_2,
+        };
+        assert!((imaginary_axis.prepare().distance_sqr(z) - 25.0).abs() < 1.0e-9);
+
+        let cross = OrbitTrap::Cross {
+            center: Complex::new(1.0, 1.0),
+            angle: 0.0,
+        };
+        assert_eq!(cross.prepare().distance_sqr(z), 4.0);
+
+        // The closest the orbit comes
+        let coloring = OrbitColoring::Trap(point);
+        let mut stats = OrbitStats::default();
+        for z in [Complex::new(3.0, 4.0), Complex::new(0.0, 1.0), z] {
+            stats.record(&coloring, z);
+        }
+        assert_eq!(stats.trap_distance_sqr, 1.0);
+
+        assert!(OrbitTrap::Point {
+            center: Complex::new(MAX_TRAP_EXTENT + 1.0, 0.0)
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn stripe_and_curvature_average_their_terms() {
+        let stripes = OrbitColoring::StripeAverage { density: 1.0 };
+        let mut stats = OrbitStats::default();
+        // sin(arg) of pi/2 and -pi/2
+        stats.record(&stripes, Complex::new(0.0, 1.0));
+        stats.record(&stripes, Complex::new(0.0, -1.0));
+        assert_eq!(stats.terms, 2);
+        assert!((stats.average(1.0) - 0.5).abs() < 1.0e-9);
+        // Without the last term
+        assert!((stats.average(0.0) - 1.0).abs() < 1.0e-9);
+
+        // Needs two previous values, then measures how sharply the orbit turns
+        let mut stats = OrbitStats::default();
+        for z in [0.0, 1.0, 2.0, 1.0] {
+            stats.record(&OrbitColoring::CurvatureAverage, Complex::new(z, 0.0));
+        }
+        assert_eq!(stats.terms, 2);
+        // Straight on, then turning right back
+        assert!((stats.average(1.0) - 0.5).abs() < 1.0e-9);
+        assert_eq!(OrbitStats::default().average(1.0), 0.0);
+    }
+
+    #[test]
+    fn histograms_equalize_counts() {
+        let histogram = IterationHistogram::new(10, [1, 1, 2, 5].into_iter());
+
+        assert_eq!(histogram.equalize(0.0), 0.0);
+        assert_eq!(histogram.equalize(2.0), 0.5);
+        assert_eq!(histogram.equalize(10.0), 1.0);
+        // Past the limit is clamped, and in between is interpolated
+        assert_eq!(histogram.equalize(50.0), 1.0);
+        assert_eq!(histogram.equalize(1.5), 0.25);
+
+        let mut previous = 0.0;
+        for mu in 0..=20 {
+            let equalized = histogram.equalize(mu as f64 / 2.0);
+            assert!(equalized >= previous);
+            previous = equalized;
+        }
+    }
+
+    #[test]
+    fn each_mode_colors_escaped_points() {
+        let (count, z, derivative) = escape(Complex::new(0.4, 0.3));
+        let trap = OrbitTrap::Point {
+            center: Complex::new(0.0, 0.0),
+        };
+
+        for mode in [
+            ColoringMode::EscapeTime,
+            ColoringMode::Smooth,
+            ColoringMode::Distance {
+                line_width: 1.0,
+                glow: 0.0,
+            },
+            ColoringMode::OrbitTrap(trap),
+            ColoringMode::StripeAverage { density: 4.0 },
+            ColoringMode::CurvatureAverage,
+            ColoringMode::Histogram,
+        ] {
+            assert!(mode.validate().is_ok());
+
+            let mut stats = OrbitStats::default();
+            if let Some(coloring) = mode.orbit_coloring() {
+                stats.record(&coloring, Complex::new(0.1, 0.1));
+                stats.record(&coloring, Complex::new(0.2, -0.3));
+                stats.record(&coloring, Complex::new(-0.4, 0.5));
+            }
+
+            let mut scheme = scheme(mode, InteriorColoring::Fixed);
+            if scheme.needs_histogram() {
+                scheme.set_histogram(IterationHistogram::new(100, [count].into_iter()));
+            }
+
+            let color = scheme.exterior_color(count, z, Some(derivative), Some(&stats));
+            assert_eq!(color.0[3], 255, "{:?}", mode);
+        }
+
+        // Only the escape count matters for the original coloring
+        let scheme = scheme(ColoringMode::EscapeTime, InteriorColoring::Fixed);
+        assert!(scheme.depends_only_on_count());
+        assert_eq!(
+            scheme.exterior_color(count, z, None, None),
+            scheme.exterior_color(count, z * 2.0, None, None)
+        );
+        assert_eq!(
+            escape_time_color(256, (83, 151, 229)),
+            escape_time_color(1, (83, 151, 229))
+        );
+    }
+
+    #[test]
+    fn interior_modes_color_the_set() {
+        let interior = |mode: InteriorColoring, z: Option<Complex<f64>>, period: Option<usize>| {
+            scheme(ColoringMode::Smooth, mode).interior_color(z, period)
+        };
+        let black = Rgba([0, 0, 0, 255]);
+
+        assert_eq!(
+            interior(InteriorColoring::Fixed, None, None),
+            INTERIOR_COLOR
+        );
+        assert_eq!(interior(InteriorColoring::Transparent, None, None).0[3], 0);
+        assert_eq!(
+            interior(InteriorColoring::Solid { position: 0.0 }, None, None),
+            black
+        );
+        assert_eq!(
+            interior(InteriorColoring::Solid { position: 0.5 }, None, None),
+            Rgba([255, 255, 255, 255])
+        );
+
+        let origin = Some(Complex::new(0.0, 0.0));
+        assert_eq!(interior(InteriorColoring::Magnitude, origin, None), black);
+        assert_ne!(
+            interior(
+                InteriorColoring::Magnitude,
+                Some(Complex::new(1.0, 0.0)),
+                None
+            ),
+            black
+        );
+        // Without an orbit, falling back to the fixed color
+        assert_eq!(
+            interior(InteriorColoring::Magnitude, None, None),
+            INTERIOR_COLOR
+        );
+
+        assert_eq!(interior(InteriorColoring::Period, None, Some(1)), black);
+        assert_ne!(
+            interior(InteriorColoring::Period, None, Some(2)),
+            interior(InteriorColoring::Period, None, Some(3))
+        );
+        assert_eq!(
+            interior(InteriorColoring::Period, None, None),
+            INTERIOR_COLOR
+        );
+
+        assert!(InteriorColoring::Solid { position: 1.5 }
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn transparent_bands_fade_out_their_counts() {
+        let hard = TransparentBand {
+            start: 10.0,
+            end: 20.0,
+            softness: 0.0,
+        };
+        assert_eq!(hard.opacity(15.0), 0.0);
+        assert_eq!(hard.opacity(5.0), 1.0);
+        assert_eq!(hard.opacity(25.0), 1.0);
+
+        let soft = TransparentBand {
+            softness: 4.0,
+            ..hard
+        };
+        assert_eq!(soft.opacity(15.0), 0.0);
+        assert_eq!(soft.opacity(22.0), 0.5);
+        assert_eq!(soft.opacity(8.0), 0.5);
+        assert_eq!(soft.opacity(30.0), 1.0);
+
+        // Applied to the alpha of escaped points
+        let (count, z, _) = escape(Complex::new(0.4, 0.3));
+        let mu = smooth_iteration_count(count, z, LN_2);
+        let mut img_params = whole_set((64, 64));
+        img_params.coloring = ColoringMode::Smooth;
+        img_params.transparent_band = Some(TransparentBand {
+            start: (mu - 1.0) as f32,
+            end: (mu + 1.0) as f32,
+            softness: 0.0,
+        });
+        let scheme = ColorScheme::from_params(&img_params, 0.01);
+        assert_eq!(scheme.exterior_color(count, z, None, None).0[3], 0);
+
+        for band in [
+            TransparentBand {
+                start: 20.0,
+                end: 10.0,
+                softness: 0.0,
+            },
+            TransparentBand {
+                softness: MAX_BAND_SOFTNESS + 1.0,
+                ..hard
+            },
+        ] {
+            assert!(band.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use num::Complex;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+
+use crate::fractal::Formula;
+use crate::image_params::{self, MAX_ITERATION_LIMIT};
+use crate::mandelbrot::{self, EscapeConfig};
+use crate::numeric;
+
+/// Half-width/height of the view discovery starts from, around the origin.
+/// Every formula's set lies within it.
+pub const START_HALF_EXTENT: f64 = 2.0;
+
+/// Number of points sampled along each side of the view at every step.
+pub const SAMPLE_GRID_SIZE: usize = 9;
+
+/// Number of times discovery starts over from the whole set before giving up.
+pub const MAX_DISCOVERY_ATTEMPTS: usize = 4;
+
+// Squared escape radius the samples are iterated to
+const BAILOUT_SQR: f64 = 4.0;
+
+/// Find a random point on the boundary of the set of `formula`, accurate to
+/// within `half_extent`, to center a view of that half-width/height on.
+///
+/// Starting from a view of the whole set, it samples a grid of escape counts
+/// and zooms in on one of the samples next to the highest count, picked with a
+/// weight of the variance of the (log) counts around it. Those are the points
+/// where the counts change the most, between the set (or where it's closest)
+/// and its surroundings, so the view narrows in on the boundary, wherever on
+/// the set that is. Each step zooms in to the square of samples around the
+/// pick, so the next grid still has a change of counts next to its highest.
+///
+/// Returns `None` if every attempt sampled a grid of equal counts.
+pub fn discover_boundary_point(
+    rng: &mut impl Rng,
+    formula: Formula,
+    half_extent: f64,
+) -> Option<Complex<f64>> {
+    (0..MAX_DISCOVERY_ATTEMPTS).find_map(|_| descend(rng, formula, half_extent))
+}
+
+fn descend(rng: &mut impl Rng, formula: Formula, target_half_extent: f64) -> Option<Complex<f64>> {
+    let mut center = Complex { re: 0.0, im: 0.0 };
+    let mut half_extent = START_HALF_EXTENT;
+
+    while half_extent > target_half_extent {
+        let limit = image_params::auto_iteration_limit(2.0 * half_extent)
+            .min(MAX_ITERATION_LIMIT as f64) as usize;
+        let config = EscapeConfig::counts_only(formula, limit, BAILOUT_SQR);
+        let spacing = 2.0 * half_extent / (SAMPLE_GRID_SIZE - 1) as f64;
+        let origin = center - Complex::new(half_extent, half_extent);
+
+        let counts: Vec<f64> = (0..SAMPLE_GRID_SIZE * SAMPLE_GRID_SIZE)
+            .map(|i| {
+                let (x, y) = (i % SAMPLE_GRID_SIZE, i / SAMPLE_GRID_SIZE);
+                let c = origin + Complex::new(x as f64 * spacing, y as f64 * spacing);
+                numeric::ln(escape_count(c, &config) as f64 + 1.0)
+            })
+            .collect();
+        let max_count = counts.iter().copied().fold(f64::MIN, f64::max);
+
+        // Every sample but those on the edge, which are missing neighbors
+        let inner: Vec<(usize, usize)> = (1..SAMPLE_GRID_SIZE - 1)
+            .flat_map(|y| (1..SAMPLE_GRID_SIZE - 1).map(move |x| (x, y)))
+            .collect();
+        let weights = inner.iter().map(|&(x, y)| {
+            let neighborhood = neighborhood(&counts, x, y);
+            // Only zoom in next to the members of the set, or to the samples
+            // closest to being members, and not into the gradients around it
+            if neighborhood.contains(&max_count) {
+                variance(&neighborhood)
+            } else {
+                0.0
+            }
+        });
+
+        // Fails when all the weights are 0, i.e. the grid is the same count
+        let picked = WeightedIndex::new(weights).ok()?.sample(rng);
+        let (x, y) = inner[picked];
+
+        center = origin + Complex::new(x as f64 * spacing, y as f64 * spacing);
+        half_extent = spacing;
+    }
+
+    Some(center)
+}
+
+// The samples in the 3x3 square around (x, y)
+fn neighborhood(counts: &[f64], x: usize, y: usize) -> Vec<f64> {
+    (y - 1..=y + 1)
+        .flat_map(|ny| (x - 1..=x + 1).map(move |nx| counts[ny * SAMPLE_GRID_SIZE + nx]))
+        .collect()
+}
+
+fn variance(samples: &[f64]) -> f64 {
+    let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+    samples
+        .iter()
+        .map(|sample| (sample - mean).powi(2))
+        .sum::<f64>()
+        / samples.len() as f64
+}
+
+// Escape count of `c`, or the iteration limit if it doesn't escape
+fn escape_count(c: Complex<f64>, config: &EscapeConfig) -> usize {
+    mandelbrot::escape_time(c, config)
+        .escaped()
+        .map_or(config.limit, |escape| escape.count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coloring::ColoringMode;
+    use crate::interestingness::Interestingness;
+    use crate::mandelbrot::tests::view;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    #[test]
+    fn discovers_windows_with_edge_detail() {
+        let half_extent = 1.0e-3;
+
+        for formula in [Formula::Quadratic, Formula::BurningShip] {
+            let mut rng = ChaCha8Rng::seed_from_u64(1);
+            let center = discover_boundary_point(&mut rng, formula, half_extent).unwrap();
+
+            let mut img_params = view(
+                (center.re - half_extent, center.im + half_extent),
+                (center.re + half_extent, center.im - half_extent),
+                (64, 64),
+                1000,
+            );
+            img_params.formula = formula;
+            img_params.coloring = ColoringMode::Smooth;
+
+            let measured = Interestingness::measure(&img_params).unwrap();
+            assert!(
+                measured.edge_density > 0.05,
+                "{:?}: {:?}",
+                formula,
+                measured
+            );
+            assert!(
+                measured.interior_ratio < 0.9,
+                "{:?}: {:?}",
+                formula,
+                measured
+            );
+        }
+    }
+}
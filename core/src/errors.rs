@@ -42,3 +42,33 @@ impl std::fmt::Display for InvalidPostProcessConfig {
         }
     }
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidImageParams {
+    Default { message: String },
+}
+
+impl std::fmt::Display for InvalidImageParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InvalidImageParams::Default { message } => {
+                write!(f, "Failed to decode image params: {}", message)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidCatalog {
+    Default { message: String },
+}
+
+impl std::fmt::Display for InvalidCatalog {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InvalidCatalog::Default { message } => {
+                write!(f, "Failed to load location catalog: {}", message)
+            }
+        }
+    }
+}
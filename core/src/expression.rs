@@ -0,0 +1,723 @@
+use num::Complex;
+use std::f64::consts::{LN_2, PI};
+
+use crate::errors;
+
+/// Maximum length of a formula, in bytes.
+pub const MAX_FORMULA_LENGTH: usize = 64;
+/// Maximum cost of one evaluation of a formula, see `Program::cost`. Roughly
+/// the number of complex multiplications it's allowed to take.
+pub const MAX_FORMULA_COST: u32 = 48;
+/// Bounds on an integer exponent, e.g. the 3 in `z^3`.
+pub const MAX_INTEGER_POWER: i32 = 16;
+
+// Values on the stack at once while evaluating
+const MAX_STACK_DEPTH: usize = 16;
+
+// Costs of each operation, relative to a complex multiplication
+const ARITHMETIC_COST: u32 = 1;
+const DIVISION_COST: u32 = 2;
+const FUNCTION_COST: u32 = 8;
+const COMPLEX_POWER_COST: u32 = 16;
+
+/// An elementary function that can be called in a formula.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Sinh,
+    Cosh,
+    Tanh,
+    Exp,
+    Ln,
+    Sqrt,
+    Conj,
+    Abs,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "sinh" => Function::Sinh,
+            "cosh" => Function::Cosh,
+            "tanh" => Function::Tanh,
+            "exp" => Function::Exp,
+            "ln" | "log" => Function::Ln,
+            "sqrt" => Function::Sqrt,
+            "conj" => Function::Conj,
+            "abs" => Function::Abs,
+            _ => return None,
+        })
+    }
+
+    fn apply(&self, w: Complex<f64>) -> Complex<f64> {
+        match self {
+            Function::Sin => w.sin(),
+            Function::Cos => w.cos(),
+            Function::Tan => w.tan(),
+            Function::Sinh => w.sinh(),
+            Function::Cosh => w.cosh(),
+            Function::Tanh => w.tanh(),
+            Function::Exp => w.exp(),
+            Function::Ln => w.ln(),
+            Function::Sqrt => w.sqrt(),
+            Function::Conj => w.conj(),
+            Function::Abs => Complex {
+                re: w.norm(),
+                im: 0.0,
+            },
+        }
+    }
+
+    fn cost(&self) -> u32 {
+        match self {
+            Function::Conj | Function::Abs => ARITHMETIC_COST,
+            _ => FUNCTION_COST,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(usize, usize),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    LeftParen,
+    RightParen,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let bytes = source.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let start = i;
+        let token = match bytes[i] {
+            b' ' | b'\t' => {
+                i += 1;
+                continue;
+            }
+            b'+' => Token::Plus,
+            b'-' => Token::Minus,
+            b'*' => Token::Star,
+            b'/' => Token::Slash,
+            b'^' => Token::Caret,
+            b'(' => Token::LeftParen,
+            b')' => Token::RightParen,
+            b'0'..=b'9' | b'.' => {
+                while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                    i += 1;
+                }
+
+                let number = source[start..i]
+                    .parse()
+                    .map_err(|_| format!("invalid number at {}", start))?;
+                tokens.push((start, Token::Number(number)));
+                continue;
+            }
+            b if b.is_ascii_alphabetic() => {
+                while i < bytes.len() && bytes[i].is_ascii_alphabetic() {
+                    i += 1;
+                }
+
+                tokens.push((start, Token::Ident(start, i)));
+                continue;
+            }
+            _ => return Err(format!("unexpected character at {}", start)),
+        };
+
+        tokens.push((start, token));
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Z,
+    C,
+    Constant(Complex<f64>),
+    Neg(Box<Node>),
+    Add(Box<Node>, Box<Node>),
+    Sub(Box<Node>, Box<Node>),
+    Mul(Box<Node>, Box<Node>),
+    Div(Box<Node>, Box<Node>),
+    Pow(Box<Node>, Box<Node>),
+    Call(Function, Box<Node>),
+}
+
+impl Node {
+    /// The value of the node, if it doesn't depend on `z` or `c`.
+    fn constant_value(&self) -> Option<Complex<f64>> {
+        match self {
+            Node::Z | Node::C => None,
+            Node::Constant(value) => Some(*value),
+            Node::Neg(node) => Some(-node.constant_value()?),
+            Node::Add(a, b) => Some(a.constant_value()? + b.constant_value()?),
+            Node::Sub(a, b) => Some(a.constant_value()? - b.constant_value()?),
+            Node::Mul(a, b) => Some(a.constant_value()? * b.constant_value()?),
+            Node::Div(a, b) => Some(a.constant_value()? / b.constant_value()?),
+            Node::Pow(a, b) => Some(a.constant_value()?.powc(b.constant_value()?)),
+            Node::Call(function, node) => Some(function.apply(node.constant_value()?)),
+        }
+    }
+
+    /// Degree of the node as a polynomial in `z`, or `None` if it isn't one.
+    fn degree(&self) -> Option<u32> {
+        match self {
+            Node::Z => Some(1),
+            Node::C | Node::Constant(_) => Some(0),
+            Node::Neg(node) | Node::Call(Function::Conj, node) => node.degree(),
+            Node::Add(a, b) | Node::Sub(a, b) => Some(a.degree()?.max(b.degree()?)),
+            Node::Mul(a, b) => Some(a.degree()? + b.degree()?),
+            Node::Div(a, b) if b.degree()? == 0 => a.degree(),
+            Node::Pow(base, exponent) => {
+                let power = integer_power(exponent)?;
+                Some(base.degree()? * u32::try_from(power).ok()?)
+            }
+            Node::Div(..) | Node::Call(..) => None,
+        }
+    }
+}
+
+// The exponent of a power, if it's a small enough integer to be evaluated with
+// repeated multiplication
+fn integer_power(exponent: &Node) -> Option<i32> {
+    let value = exponent.constant_value()?;
+
+    if value.im == 0.0 && value.re.fract() == 0.0 && value.re.abs() <= MAX_INTEGER_POWER as f64 {
+        Some(value.re as i32)
+    } else {
+        None
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.position).map(|(_, token)| *token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+
+    fn error(&self, message: &str) -> String {
+        match self.tokens.get(self.position) {
+            Some((offset, _)) => format!("{} at {}", message, offset),
+            None => format!("{} at end of formula", message),
+        }
+    }
+
+    // expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<Node, String> {
+        let mut node = self.term()?;
+
+        loop {
+            node = match self.peek() {
+                Some(Token::Plus) => {
+                    self.next();
+                    Node::Add(Box::new(node), Box::new(self.term()?))
+                }
+                Some(Token::Minus) => {
+                    self.next();
+                    Node::Sub(Box::new(node), Box::new(self.term()?))
+                }
+                _ => return Ok(node),
+            };
+        }
+    }
+
+    // term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+
+        loop {
+            node = match self.peek() {
+                Some(Token::Star) => {
+                    self.next();
+                    Node::Mul(Box::new(node), Box::new(self.unary()?))
+                }
+                Some(Token::Slash) => {
+                    self.next();
+                    Node::Div(Box::new(node), Box::new(self.unary()?))
+                }
+                _ => return Ok(node),
+            };
+        }
+    }
+
+    // unary := '-' unary | power
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.peek() == Some(Token::Minus) {
+            self.next();
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+
+        self.power()
+    }
+
+    // power := atom ('^' unary)?, so `z^-2` works and `z^2^3` is `z^(2^3)`
+    fn power(&mut self) -> Result<Node, String> {
+        let base = self.atom()?;
+
+        if self.peek() == Some(Token::Caret) {
+            self.next();
+            return Ok(Node::Pow(Box::new(base), Box::new(self.unary()?)));
+        }
+
+        Ok(base)
+    }
+
+    // atom := number | 'z' | 'c' | 'i' | 'pi' | function '(' expression ')'
+    //       | '(' expression ')'
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some(Token::Number(value)) => {
+                self.next();
+                Ok(Node::Constant(Complex { re: value, im: 0.0 }))
+            }
+            Some(Token::Ident(start, end)) => {
+                let name = &self.source[start..end];
+                let node = match name {
+                    "z" => Node::Z,
+                    "c" => Node::C,
+                    "i" => Node::Constant(Complex { re: 0.0, im: 1.0 }),
+                    "pi" => Node::Constant(Complex { re: PI, im: 0.0 }),
+                    _ => {
+                        let function = Function::from_name(name)
+                            .ok_or_else(|| self.error(&format!("unknown name '{}'", name)))?;
+                        self.next();
+
+                        return Ok(Node::Call(function, Box::new(self.parenthesized()?)));
+                    }
+                };
+
+                self.next();
+                Ok(node)
+            }
+            Some(Token::LeftParen) => self.parenthesized(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn parenthesized(&mut self) -> Result<Node, String> {
+        if self.next() != Some(Token::LeftParen) {
+            self.position -= 1;
+            return Err(self.error("expected '('"));
+        }
+
+        let node = self.expression()?;
+
+        if self.next() != Some(Token::RightParen) {
+            self.position -= 1;
+            return Err(self.error("expected ')'"));
+        }
+
+        Ok(node)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    PushZ,
+    PushC,
+    Push(Complex<f64>),
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    PowI(i32),
+    Pow,
+    Call(Function),
+}
+
+/// A formula compiled to a sequence of stack operations, to evaluate
+/// `z = f(z, c)` without walking the parsed expression for every iteration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    ops: Vec<Op>,
+    cost: u32,
+    degree: Option<u32>,
+}
+
+impl Program {
+    /// Parse and compile a formula such as `z^3 + c*sin(z)`.
+    ///
+    /// Formulas are made of `z`, `c`, real numbers, `i`, `pi`, the operators
+    /// `+ - * / ^`, parentheses, and the functions `sin cos tan sinh cosh tanh
+    /// exp ln (or log) sqrt conj abs`. They're limited to
+    /// `MAX_FORMULA_LENGTH` bytes and a cost of `MAX_FORMULA_COST`.
+    pub fn compile(source: &str) -> Result<Self, errors::InvalidImageParams> {
+        Self::compile_source(source).map_err(|message| errors::InvalidImageParams::Default {
+            message: format!("invalid formula: {}", message),
+        })
+    }
+
+    fn compile_source(source: &str) -> Result<Self, String> {
+        if source.len() > MAX_FORMULA_LENGTH {
+            return Err(format!("longer than {} characters", MAX_FORMULA_LENGTH));
+        }
+
+        let mut parser = Parser {
+            source,
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let root = parser.expression()?;
+
+        if parser.peek().is_some() {
+            return Err(parser.error("unexpected input"));
+        }
+
+        let mut program = Self {
+            ops: vec![],
+            cost: 0,
+            degree: root.degree(),
+        };
+        let max_depth = program.emit(&root, 0)?;
+
+        if max_depth > MAX_STACK_DEPTH {
+            return Err("too deeply nested".to_string());
+        }
+
+        if program.cost > MAX_FORMULA_COST {
+            return Err(format!(
+                "too expensive, costs {} of at most {}",
+                program.cost, MAX_FORMULA_COST
+            ));
+        }
+
+        Ok(program)
+    }
+
+    // Append the ops for `node`, given `depth` values are already on the stack,
+    // and return the greatest stack depth reached
+    fn emit(&mut self, node: &Node, depth: usize) -> Result<usize, String> {
+        // Constant subexpressions are folded into a single value
+        if let Some(value) = node.constant_value() {
+            self.ops.push(Op::Push(value));
+            return Ok(depth + 1);
+        }
+
+        let (op, cost, max_depth) = match node {
+            Node::Z => (Op::PushZ, 0, depth + 1),
+            Node::C => (Op::PushC, 0, depth + 1),
+            Node::Constant(value) => (Op::Push(*value), 0, depth + 1),
+            Node::Neg(node) => (Op::Neg, 0, self.emit(node, depth)?),
+            Node::Call(function, node) => (
+                Op::Call(*function),
+                function.cost(),
+                self.emit(node, depth)?,
+            ),
+            Node::Pow(base, exponent) => match integer_power(exponent) {
+                Some(power) => {
+                    // Exponentiation by squaring, with a multiplication for
+                    // each bit past the first, and a division for negative
+                    // powers
+                    let n = power.unsigned_abs().max(1);
+                    let multiplications = 31 - n.leading_zeros() + n.count_ones() - 1;
+                    let cost = multiplications * ARITHMETIC_COST
+                        + if power < 0 { DIVISION_COST } else { 0 };
+
+                    (Op::PowI(power), cost, self.emit(base, depth)?)
+                }
+                None => {
+                    let left = self.emit(base, depth)?;
+                    let right = self.emit(exponent, depth + 1)?;
+
+                    (Op::Pow, COMPLEX_POWER_COST, left.max(right))
+                }
+            },
+            Node::Add(a, b) | Node::Sub(a, b) | Node::Mul(a, b) | Node::Div(a, b) => {
+                let left = self.emit(a, depth)?;
+                let right = self.emit(b, depth + 1)?;
+                let (op, cost) = match node {
+                    Node::Add(..) => (Op::Add, ARITHMETIC_COST),
+                    Node::Sub(..) => (Op::Sub, ARITHMETIC_COST),
+                    Node::Mul(..) => (Op::Mul, ARITHMETIC_COST),
+                    _ => (Op::Div, DIVISION_COST),
+                };
+
+                (op, cost, left.max(right))
+            }
+        };
+
+        self.ops.push(op);
+        self.cost += cost;
+
+        Ok(max_depth)
+    }
+
+    /// Cost of one evaluation, with a complex multiplication costing 1.
+    pub fn cost(&self) -> u32 {
+        self.cost
+    }
+
+    /// Natural log of the degree of the formula in `z`, for smooth coloring.
+    /// Formulas that aren't polynomials in `z` are treated as quadratic.
+    pub fn ln_degree(&self) -> f64 {
+        match self.degree {
+            Some(degree) if degree > 2 => (degree as f64).ln(),
+            _ => LN_2,
+        }
+    }
+
+    /// Evaluate the formula for the current `z` and `c`.
+    pub fn eval(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let mut stack = [Complex { re: 0.0, im: 0.0 }; MAX_STACK_DEPTH];
+        let mut top = 0;
+
+        for op in &self.ops {
+            match *op {
+                Op::PushZ => {
+                    stack[top] = z;
+                    top += 1;
+                }
+                Op::PushC => {
+                    stack[top] = c;
+                    top += 1;
+                }
+                Op::Push(value) => {
+                    stack[top] = value;
+                    top += 1;
+                }
+                Op::Neg => stack[top - 1] = -stack[top - 1],
+                Op::PowI(power) => stack[top - 1] = stack[top - 1].powi(power),
+                Op::Call(function) => stack[top - 1] = function.apply(stack[top - 1]),
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => {
+                    top -= 1;
+                    let (a, b) = (stack[top - 1], stack[top]);
+
+                    stack[top - 1] = match *op {
+                        Op::Add => a + b,
+                        Op::Sub => a - b,
+                        Op::Mul => a * b,
+                        Op::Div => a / b,
+                        _ => a.powc(b),
+                    };
+                }
+            }
+        }
+
+        stack[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Node, String> {
+        let mut parser = Parser {
+            source,
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let root = parser.expression()?;
+
+        match parser.peek() {
+            Some(_) => Err(parser.error("unexpected input")),
+            None => Ok(root),
+        }
+    }
+
+    fn constant(re: f64) -> Box<Node> {
+        Box::new(Node::Constant(Complex { re, im: 0.0 }))
+    }
+
+    fn assert_close(a: Complex<f64>, b: Complex<f64>) {
+        assert!(
+            (a - b).norm() < 1.0e-12 * b.norm().max(1.0),
+            "{} != {}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn tokenizes_with_offsets() {
+        assert_eq!(
+            tokenize("z^2 +\t1.5*sin(c)"),
+            Ok(vec![
+                (0, Token::Ident(0, 1)),
+                (1, Token::Caret),
+                (2, Token::Number(2.0)),
+                (4, Token::Plus),
+                (6, Token::Number(1.5)),
+                (9, Token::Star),
+                (10, Token::Ident(10, 13)),
+                (13, Token::LeftParen),
+                (14, Token::Ident(14, 15)),
+                (15, Token::RightParen),
+            ])
+        );
+
+        assert_eq!(
+            tokenize("z + 1.2.3"),
+            Err("invalid number at 4".to_string())
+        );
+        assert_eq!(
+            tokenize("z % c"),
+            Err("unexpected character at 2".to_string())
+        );
+        assert_eq!(tokenize("zé"), Err("unexpected character at 1".to_string()));
+    }
+
+    #[test]
+    fn parses_with_precedence() {
+        let (z, c) = (|| Box::new(Node::Z), || Box::new(Node::C));
+
+        // Left associative, with * and / before + and -
+        assert_eq!(
+            parse("z - c - 1"),
+            Ok(Node::Sub(Box::new(Node::Sub(z(), c())), constant(1.0)))
+        );
+        assert_eq!(
+            parse("z + c*2/z"),
+            Ok(Node::Add(
+                z(),
+                Box::new(Node::Div(Box::new(Node::Mul(c(), constant(2.0))), z()))
+            ))
+        );
+
+        // Powers before negation, and right associative
+        assert_eq!(
+            parse("-z^2"),
+            Ok(Node::Neg(Box::new(Node::Pow(z(), constant(2.0)))))
+        );
+        assert_eq!(
+            parse("z^-2"),
+            Ok(Node::Pow(z(), Box::new(Node::Neg(constant(2.0)))))
+        );
+        assert_eq!(
+            parse("z^2^3"),
+            Ok(Node::Pow(
+                z(),
+                Box::new(Node::Pow(constant(2.0), constant(3.0)))
+            ))
+        );
+
+        assert_eq!(
+            parse("log((z))*i"),
+            Ok(Node::Mul(
+                Box::new(Node::Call(Function::Ln, z())),
+                Box::new(Node::Constant(Complex { re: 0.0, im: 1.0 }))
+            ))
+        );
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_eq!(
+            parse("z +"),
+            Err("expected a value at end of formula".to_string())
+        );
+        assert_eq!(parse("z * )"), Err("expected a value at 4".to_string()));
+        assert_eq!(parse("sin z"), Err("expected '(' at 4".to_string()));
+        assert_eq!(
+            parse("(z + c"),
+            Err("expected ')' at end of formula".to_string())
+        );
+        assert_eq!(parse("foo(z)"), Err("unknown name 'foo' at 0".to_string()));
+        assert_eq!(parse("z c"), Err("unexpected input at 2".to_string()));
+        assert_eq!(
+            parse(""),
+            Err("expected a value at end of formula".to_string())
+        );
+    }
+
+    #[test]
+    fn evaluates_formulas() {
+        let (z, c) = (Complex::new(0.3, -0.7), Complex::new(-0.8, 0.156));
+
+        let cases: [(&str, Complex<f64>); 5] = [
+            ("z^2 + c", z * z + c),
+            ("z^-3 - c/2", (z * z * z).inv() - c / 2.0),
+            ("conj(z)^2 + c", z.conj() * z.conj() + c),
+            ("z^3 + c*sin(z)", z * z * z + c * z.sin()),
+            ("z^(1 + i) + c", z.powc(Complex::new(1.0, 1.0)) + c),
+        ];
+
+        for (source, expected) in cases {
+            assert_close(Program::compile(source).unwrap().eval(z, c), expected);
+        }
+    }
+
+    #[test]
+    fn folds_constants() {
+        let program = Program::compile("z*(2*pi - sqrt(4))").unwrap();
+
+        assert_eq!(
+            program.ops,
+            vec![
+                Op::PushZ,
+                Op::Push(Complex {
+                    re: 2.0 * PI - 2.0,
+                    im: 0.0
+                }),
+                Op::Mul
+            ]
+        );
+        assert_eq!(program.cost(), ARITHMETIC_COST);
+    }
+
+    #[test]
+    fn finds_degrees() {
+        let ln_degree = |source| Program::compile(source).unwrap().ln_degree();
+
+        assert_eq!(ln_degree("z^3 + c"), 3.0_f64.ln());
+        assert_eq!(ln_degree("(z*z)^2 - z + c"), 4.0_f64.ln());
+        assert_eq!(ln_degree("conj(z)^5/2 + c"), 5.0_f64.ln());
+        // Not polynomials, treated as quadratic
+        assert_eq!(ln_degree("z^3 + c*sin(z)"), LN_2);
+        assert_eq!(ln_degree("z^-2 + c"), LN_2);
+    }
+
+    #[test]
+    fn enforces_limits() {
+        // Length
+        let longest = format!("z{}", " ".repeat(MAX_FORMULA_LENGTH - 1));
+        assert!(Program::compile(&longest).is_ok());
+        assert!(Program::compile(&format!("{} ", longest)).is_err());
+
+        // Cost, of 8 per function call
+        let calls = |n: usize| format!("{}z{}", "sin(".repeat(n), ")".repeat(n));
+        assert_eq!(
+            Program::compile(&calls(6)).unwrap().cost(),
+            MAX_FORMULA_COST
+        );
+        assert!(Program::compile(&calls(7)).is_err());
+
+        // Integer powers are squared and multiplied, others aren't
+        let cost = |source| Program::compile(source).unwrap().cost();
+        assert_eq!(cost("z^16"), 4);
+        assert_eq!(cost("z^15"), 6);
+        assert_eq!(cost("z^-2"), 1 + DIVISION_COST);
+        assert_eq!(cost("z^17"), COMPLEX_POWER_COST);
+
+        // Stack depth, of 2 more than the nested parentheses
+        let nested = |n: usize| format!("{}z+z{}", "z+(".repeat(n), ")".repeat(n));
+        assert!(Program::compile(&nested(MAX_STACK_DEPTH - 2)).is_ok());
+        assert!(Program::compile(&nested(MAX_STACK_DEPTH - 1)).is_err());
+        assert!(nested(MAX_STACK_DEPTH - 1).len() <= MAX_FORMULA_LENGTH);
+    }
+}
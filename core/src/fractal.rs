@@ -0,0 +1,387 @@
+use num::Complex;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::{LN_2, TAU};
+
+use crate::errors;
+use crate::image_params::ComplexDef;
+use crate::numeric::{self, Arithmetic, RenderFloat};
+
+/// Largest magnitude of a Julia set constant. Past 2, every orbit escapes and
+/// the Julia set is a sparse dust of points.
+pub const MAX_JULIA_C_NORM: f64 = 2.0;
+
+/// Bounds on the power of a `Formula::Multibrot`.
+pub const MIN_MULTIBROT_POWER: u8 = 3;
+pub const MAX_MULTIBROT_POWER: u8 = 8;
+
+/// The formula iterated for each point, `z = f(z) + c`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Formula {
+    /// `z^2 + c`, the formula of the Mandelbrot set itself.
+    #[default]
+    Quadratic,
+    /// `(|re(z)| + i|im(z)|)^2 + c`
+    BurningShip,
+    /// `conj(z)^2 + c`, also known as the Mandelbar set.
+    Tricorn,
+    /// `z^n + c`, for a power from `MIN_MULTIBROT_POWER` to
+    /// `MAX_MULTIBROT_POWER`.
+    Multibrot(u8),
+    /// `z^2 + c`, with the absolute value of the real part of `z^2`.
+    Celtic,
+    /// `conj(z)^2 + c`, with the absolute value of the real part of
+    /// `conj(z)^2`.
+    CelticMandelbar,
+    /// `z^2 + c`, with the absolute value of the real part of `z^2` and of the
+    /// real part of `z` in the imaginary part of `z^2`.
+    CelticHeart,
+}
+
+impl Formula {
+    /// All of the formulas (with every multibrot power).
+    pub fn all() -> Vec<Formula> {
+        let mut formulas = vec![
+            Formula::Quadratic,
+            Formula::BurningShip,
+            Formula::Tricorn,
+            Formula::Celtic,
+            Formula::CelticMandelbar,
+            Formula::CelticHeart,
+        ];
+        formulas.extend((MIN_MULTIBROT_POWER..=MAX_MULTIBROT_POWER).map(Formula::Multibrot));
+
+        formulas
+    }
+
+    /// Check the formula can be rendered, as it's read from user-supplied
+    /// tokens.
+    pub fn validate(&self) -> Result<(), errors::InvalidImageParams> {
+        if let Formula::Multibrot(power) = self {
+            if !(MIN_MULTIBROT_POWER..=MAX_MULTIBROT_POWER).contains(power) {
+                return Err(errors::InvalidImageParams::Default {
+                    message: format!(
+                        "multibrot powers must be from {} to {}",
+                        MIN_MULTIBROT_POWER, MAX_MULTIBROT_POWER
+                    ),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Natural log of the degree of the formula in `z`, which sets how fast
+    /// orbits grow once they've escaped.
+    pub fn ln_degree(&self) -> f64 {
+        match self {
+            Formula::Multibrot(power) => numeric::ln(*power as f64),
+            _ => LN_2,
+        }
+    }
+
+    /// One iteration of the formula, `f(z) + c`.
+    pub fn step<T: Arithmetic>(&self, z: Complex<T>, c: Complex<T>) -> Complex<T> {
+        // Real and imaginary parts of z^2, in the same order of operations as
+        // `Complex<f64>`
+        let sqr = |re: T, im: T| (re * re - im * im, re * im + im * re);
+
+        let (re, im) = match *self {
+            Formula::Quadratic => sqr(z.re, z.im),
+            Formula::BurningShip => sqr(z.re.abs(), z.im.abs()),
+            Formula::Tricorn => {
+                let (re, im) = sqr(z.re, z.im);
+                (re, -im)
+            }
+            Formula::Multibrot(power) => {
+                let (mut re, mut im) = (z.re, z.im);
+                for _ in 1..power {
+                    (re, im) = (re * z.re - im * z.im, re * z.im + im * z.re);
+                }
+                (re, im)
+            }
+            Formula::Celtic => {
+                let (re, im) = sqr(z.re, z.im);
+                (re.abs(), im)
+            }
+            Formula::CelticMandelbar => {
+                let (re, im) = sqr(z.re, z.im);
+                (re.abs(), -im)
+            }
+            Formula::CelticHeart => {
+                let (re, _) = sqr(z.re, z.im);
+                let (_, im) = sqr(z.re.abs(), z.im);
+                (re.abs(), im)
+            }
+        };
+
+        Complex {
+            re: re + c.re,
+            im: im + c.im,
+        }
+    }
+
+    /// The derivative of `f` at `z`, applied to `dz`, for carrying the
+    /// derivative of an orbit along with it by the chain rule.
+    ///
+    /// The formulas that fold `z` with absolute values aren't holomorphic, so
+    /// for those this applies their Jacobian to `dz` as a vector. Folds only
+    /// flip signs, so the magnitude this gives is still accurate.
+    pub fn derivative(&self, z: Complex<f64>, dz: Complex<f64>) -> Complex<f64> {
+        let sign = |value: f64| if value < 0.0 { -1.0 } else { 1.0 };
+        // Sign of the real part of z^2, which the celtic formulas fold
+        let sqr_re_sign = sign(z.re * z.re - z.im * z.im);
+
+        match *self {
+            Formula::Quadratic => 2.0 * z * dz,
+            Formula::BurningShip => {
+                let folded_z = Complex::new(z.re.abs(), z.im.abs());
+                let folded_dz = Complex::new(sign(z.re) * dz.re, sign(z.im) * dz.im);
+                2.0 * folded_z * folded_dz
+            }
+            Formula::Tricorn => (2.0 * z * dz).conj(),
+            Formula::Multibrot(power) => power as f64 * z.powu(power as u32 - 1) * dz,
+            Formula::Celtic => {
+                let d = 2.0 * z * dz;
+                Complex::new(sqr_re_sign * d.re, d.im)
+            }
+            Formula::CelticMandelbar => {
+                let d = (2.0 * z * dz).conj();
+                Complex::new(sqr_re_sign * d.re, d.im)
+            }
+            Formula::CelticHeart => Complex::new(
+                sqr_re_sign * 2.0 * (z.re * dz.re - z.im * dz.im),
+                2.0 * (sign(z.re) * dz.re * z.im + z.re.abs() * dz.im),
+            ),
+        }
+    }
+}
+
+/// Which fractal to iterate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FractalKind {
+    /// `z = f(z) + c`, from `z = 0`, with `c` the point being rendered.
+    #[default]
+    Mandelbrot,
+    /// `z = f(z) + c`, from `z` being the point being rendered, with a fixed
+    /// `c` for the whole image.
+    Julia {
+        #[serde(with = "ComplexDef")]
+        c: Complex<f64>,
+    },
+}
+
+impl FractalKind {
+    /// Check the fractal can be rendered, as it's read from user-supplied
+    /// tokens.
+    pub fn validate(&self) -> Result<(), errors::InvalidImageParams> {
+        if let FractalKind::Julia { c } = self {
+            if !numeric::norm(*c).is_finite() || numeric::norm(*c) > MAX_JULIA_C_NORM {
+                return Err(errors::InvalidImageParams::Default {
+                    message: format!(
+                        "julia set constants must be within {} of the origin",
+                        MAX_JULIA_C_NORM
+                    ),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The derivative of the starting `z` with respect to the point being
+    /// rendered.
+    pub fn initial_derivative(&self) -> Complex<f64> {
+        match self {
+            FractalKind::Mandelbrot => Complex::new(0.0, 0.0),
+            FractalKind::Julia { .. } => Complex::new(1.0, 0.0),
+        }
+    }
+
+    /// The derivative of `c` with respect to the point being rendered, added
+    /// to the derivative of the orbit after each step.
+    pub fn constant_derivative(&self) -> Complex<f64> {
+        match self {
+            FractalKind::Mandelbrot => Complex::new(1.0, 0.0),
+            FractalKind::Julia { .. } => Complex::new(0.0, 0.0),
+        }
+    }
+
+    /// The starting `z` and the constant `c` to iterate a point with.
+    pub fn initial_orbit<T: RenderFloat>(&self, point: Complex<T>) -> (Complex<T>, Complex<T>) {
+        match *self {
+            FractalKind::Mandelbrot => {
+                let zero = T::from_f64(0.0);
+                (Complex { re: zero, im: zero }, point)
+            }
+            FractalKind::Julia { c } => (point, numeric::complex_from_f64(c)),
+        }
+    }
+}
+
+/// A random Julia set constant just inside the boundary of the Mandelbrot set,
+/// where Julia sets are connected but have the most intricate detail.
+///
+/// Picks a point on the main cardioid or the period 2 bulb, pulled slightly
+/// into the component. Both are parameterized by the multiplier of their
+/// attracting cycle, which has a magnitude under 1 inside the component.
+pub fn rand_julia_c(rng: &mut impl Rng) -> Complex<f64> {
+    let angle = rng.gen_range(0.0..TAU);
+    let multiplier = numeric::from_polar(rng.gen_range(0.95..1.0), angle);
+
+    if rng.gen_bool(0.75) {
+        // Main cardioid, c = m/2 - m^2/4
+        multiplier / 2.0 - multiplier * multiplier / 4.0
+    } else {
+        // Period 2 bulb, c = m/4 - 1
+        multiplier / 4.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether the orbit of `c` stays within the escape radius for `limit`
+    // iterations
+    fn stays_bounded(formula: Formula, c: Complex<f64>, limit: usize) -> bool {
+        let mut z = Complex::new(0.0, 0.0);
+        for _ in 0..limit {
+            if z.norm_sqr() > 4.0 {
+                return false;
+            }
+            z = formula.step(z, c);
+        }
+
+        true
+    }
+
+    #[test]
+    fn steps_known_points() {
+        let z = Complex::new(1.0, 2.0);
+        let zero = Complex::new(0.0, 0.0);
+        let c = Complex::new(0.5, -0.25);
+
+        for (formula, z, expected) in [
+            (Formula::Quadratic, z, Complex::new(-3.0, 4.0)),
+            (Formula::BurningShip, -z, Complex::new(-3.0, 4.0)),
+            (Formula::Tricorn, z, Complex::new(-3.0, -4.0)),
+            (
+                Formula::Multibrot(3),
+                Complex::new(1.0, 1.0),
+                Complex::new(-2.0, 2.0),
+            ),
+            (Formula::Celtic, z, Complex::new(3.0, 4.0)),
+            (Formula::CelticMandelbar, z, Complex::new(3.0, -4.0)),
+            (
+                Formula::CelticHeart,
+                Complex::new(-1.0, 2.0),
+                Complex::new(3.0, 4.0),
+            ),
+        ] {
+            assert_eq!(formula.step(z, zero), expected, "{:?}", formula);
+            assert_eq!(formula.step(z, c), expected + c, "{:?}", formula);
+        }
+
+        // z^n + c matches powers of z
+        for power in MIN_MULTIBROT_POWER..=MAX_MULTIBROT_POWER {
+            let z = Complex::new(0.6, -0.7);
+            let step = Formula::Multibrot(power).step(z, zero);
+            assert!((step - z.powu(power as u32)).norm() < 1.0e-12);
+        }
+    }
+
+    #[test]
+    fn known_points_escape_or_stay_bounded() {
+        for formula in Formula::all() {
+            // 0 is a fixed point of every formula, and everything escapes
+            // from c far enough out
+            assert!(
+                stays_bounded(formula, Complex::new(0.0, 0.0), 1000),
+                "{:?}",
+                formula
+            );
+            for c in [
+                Complex::new(1.0, 0.0),
+                Complex::new(0.0, 3.0),
+                Complex::new(-2.5, 0.0),
+            ] {
+                assert!(!stays_bounded(formula, c, 1000), "{:?} {}", formula, c);
+            }
+        }
+
+        // The period 2 bulb and the tip of the antenna of z^2 + c
+        assert!(stays_bounded(
+            Formula::Quadratic,
+            Complex::new(-1.0, 0.0),
+            1000
+        ));
+        assert!(stays_bounded(
+            Formula::Quadratic,
+            Complex::new(-2.0, 0.0),
+            1000
+        ));
+        // Just past the cusp of the main cardioid
+        assert!(!stays_bounded(
+            Formula::Quadratic,
+            Complex::new(0.26, 0.0),
+            1000
+        ));
+        // On the burning ship's antenna, along the negative real axis
+        assert!(stays_bounded(
+            Formula::BurningShip,
+            Complex::new(-1.75, 0.0),
+            1000
+        ));
+    }
+
+    #[test]
+    fn derivatives_match_finite_differences() {
+        let h = 1.0e-7;
+        let zero = Complex::new(0.0, 0.0);
+
+        for formula in Formula::all() {
+            // Away from the folds along the axes and the diagonals
+            for z in [Complex::new(0.7, -0.4), Complex::new(-1.3, 0.5)] {
+                for dz in [
+                    Complex::new(1.0, 0.0),
+                    Complex::new(0.0, 1.0),
+                    Complex::new(0.3, 0.8),
+                ] {
+                    let finite = (formula.step(z + h * dz, zero) - formula.step(z, zero)) / h;
+                    let derivative = formula.derivative(z, dz);
+
+                    assert!(
+                        (finite - derivative).norm() < 1.0e-5 * derivative.norm().max(1.0),
+                        "{:?} at {} along {}: {} vs {}",
+                        formula,
+                        z,
+                        dz,
+                        derivative,
+                        finite
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_settings() {
+        assert!(Formula::Multibrot(MIN_MULTIBROT_POWER - 1)
+            .validate()
+            .is_err());
+        assert!(Formula::Multibrot(MAX_MULTIBROT_POWER + 1)
+            .validate()
+            .is_err());
+        assert!(Formula::all()
+            .iter()
+            .all(|formula| formula.validate().is_ok()));
+
+        let julia = |re: f64, im: f64| FractalKind::Julia {
+            c: Complex::new(re, im),
+        };
+        assert!(julia(-0.8, 0.156).validate().is_ok());
+        assert!(julia(MAX_JULIA_C_NORM, 1.0).validate().is_err());
+        assert!(julia(f64::NAN, 0.0).validate().is_err());
+    }
+}
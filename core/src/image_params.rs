@@ -1,17 +1,66 @@
 use enumflags2::{bitflags, BitFlags};
 use log::debug;
 use num::Complex;
-use rand;
-use rand::Rng;
+use percent_encoding::percent_decode_str;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
 
+use crate::catalog::{self, Catalog, Location};
+use crate::coloring::{ColoringMode, InteriorColoring, OrbitTrap, TransparentBand};
+use crate::discovery;
 use crate::errors;
+use crate::expression::Program;
+use crate::fractal::{self, Formula, FractalKind};
+use crate::interestingness;
+use crate::landmarks;
+use crate::palette::{Palette, PaletteKind};
+use crate::perturbation::DeepZoom;
 
-// Static image dimensions (for now)
+// Default image dimensions, also used as the reference frame when picking a
+// random region in `new_from_rand`
 pub const OUTPUT_WIDTH: usize = 300;
 pub const OUTPUT_HEIGHT: usize = 300;
 
-// Interesting start points on the set
+// Default limits on the width/height of a rendered image
+pub const MIN_OUTPUT_DIMENSION: usize = 16;
+pub const MAX_OUTPUT_DIMENSION: usize = 2048;
+
+// Escape-time iteration limit of the original renderer, and the default for
+// tokens that don't set one
+pub const DEFAULT_ITERATION_LIMIT: u32 = 255;
+// Upper bound on iterations per pixel, whether set explicitly or automatically
+pub const MAX_ITERATION_LIMIT: u32 = 10_000;
+
+// Bounds on a custom bailout radius. Anything under 2 would misclassify points
+// as escaping, and beyond the max there's no visible difference
+pub const MIN_BAILOUT_RADIUS: f64 = 2.0;
+pub const MAX_BAILOUT_RADIUS: f64 = 1.0e6;
+
+// Upper bound on the samples per pixel axis when antialiasing, so a pixel
+// costs at most MAX_SUPERSAMPLING^2 points
+pub const MAX_SUPERSAMPLING: u8 = 4;
+
+// Upper bound on the work of rendering an image, see `render_work`. About 10s
+// of iterating on a single thread, in the worst case
+pub const MAX_RENDER_WORK: u64 = 10_000_000_000;
+// Cost of a custom formula's iteration on top of `Program::cost`, for going
+// through the stack machine rather than the built-in formulas
+const CUSTOM_FORMULA_OVERHEAD: u64 = 4;
+
+// Longest identity, in bytes, that `identity_from_path` accepts
+pub const MAX_IDENTITY_LEN: usize = 256;
+// Longest token, in base64 characters, that the servers accept. Long enough
+// for a deep zoom at its full precision, with every other extension set
+pub const MAX_TOKEN_LEN: usize = 1024;
+
+// Chance of `new_from_rand` picking a Julia set rather than a region of the
+// Mandelbrot set
+pub const JULIA_PROBABILITY: f64 = 0.3;
+
+// Interesting start points on the set, for when `discovery` finds nothing
 pub const INTERESTING_SELECTIONS: [(Complex<f64>, Complex<f64>); 1] = [(
     Complex {
         re: -1.20,
@@ -20,11 +69,164 @@ pub const INTERESTING_SELECTIONS: [(Complex<f64>, Complex<f64>); 1] = [(
     Complex { re: -1.0, im: 0.20 },
 )];
 
+// Distance from the center of a random view to its sides at a zoom factor of
+// 1, as wide as views zoomed in on the `INTERESTING_SELECTIONS` rectangle
+pub const DISCOVERED_HALF_EXTENT: f64 = 0.2;
+
+// Chance of `new_from_rand` zooming in on a location from its catalog, rather
+// than on a region it discovers
+pub const CATALOG_PROBABILITY: f64 = 0.5;
+
+// Chance of `new_from_rand` framing a minibrot in a region it discovers on the
+// z^2 + c set, rather than the region itself
+pub const MINIBROT_PROBABILITY: f64 = 0.25;
+// Range of the half-width/height of a view of a minibrot, in multiples of its
+// size (a minibrot is about 2.25 times its size wide)
+const MINIBROT_FRAMING: (f64, f64) = (1.5, 6.0);
+
+// Chance of `new_from_rand` picking one of the other formulas over z^2 + c
+pub const VARIANT_FORMULA_PROBABILITY: f64 = 0.4;
+
+// Default score a random image must reach, see `RandOptions`
+pub const DEFAULT_MIN_SCORE: f64 = 0.2;
+// Default number of random images scored before settling for the best
+pub const DEFAULT_MAX_RAND_ATTEMPTS: usize = 8;
+// Default most `render_work` of a random image at its own size, low enough for
+// the edge worker to render it, and enough for the automatic iteration limit
+// of the deepest zooms at the default size
+pub const DEFAULT_RAND_MAX_WORK: u64 = 250_000_000;
+
+// Chance of `new_from_rand` drawing line art, for formulas it suits
+pub const LINE_ART_PROBABILITY: f64 = 0.15;
+
+// Chance of `new_from_rand` coloring by an orbit trap or average instead
+pub const ORBIT_COLORING_PROBABILITY: f64 = 0.15;
+
+// Chance of `new_from_rand` equalizing the smooth coloring it otherwise uses
+pub const HISTOGRAM_COLORING_PROBABILITY: f64 = 0.5;
+
+/// Iteration limit `IterationLimit::Auto` picks for a view `span` units
+/// wide/high, before clamping to `MAX_ITERATION_LIMIT`.
+pub fn auto_iteration_limit(span: f64) -> f64 {
+    // Magnification relative to a view of the whole set, ~4 units wide
+    let magnification = 4.0 / span;
+
+    // A common rule of thumb for how many iterations it takes to resolve the
+    // detail at a magnification
+    let auto = 66.5 * (2.0 * (1.0 - (5.0 * magnification).sqrt()).abs().sqrt()).sqrt();
+
+    if auto.is_finite() {
+        auto.max(DEFAULT_ITERATION_LIMIT as f64)
+    } else {
+        MAX_ITERATION_LIMIT as f64
+    }
+}
+
+/// The identity to pass to `ImageParams::from_identity` for the last segment
+/// of an identity image's path, already percent-decoded (as by actix-web), and
+/// with or without a `.png` extension.
+pub fn identity_from_path(segment: &str) -> Result<&str, errors::InvalidImageParams> {
+    let identity = segment.strip_suffix(".png").unwrap_or(segment);
+
+    if identity.trim().is_empty() || identity.len() > MAX_IDENTITY_LEN {
+        return Err(errors::InvalidImageParams::Default {
+            message: format!("identity must be 1 to {} bytes", MAX_IDENTITY_LEN),
+        });
+    }
+
+    Ok(identity)
+}
+
+/// Same as `identity_from_path`, for a segment that's still percent-encoded
+/// (as by the worker's router), so the same identity gets the same image
+/// either way.
+pub fn identity_from_encoded_path(segment: &str) -> Result<String, errors::InvalidImageParams> {
+    let segment = percent_decode_str(segment).decode_utf8().map_err(|e| {
+        errors::InvalidImageParams::Default {
+            message: format!("invalid identity encoding: {}", e),
+        }
+    })?;
+
+    identity_from_path(&segment).map(str::to_string)
+}
+
+// A random index into a slice of `len` items. Ranges of usize are sampled from
+// differently on 32 and 64-bit targets, so this samples a u32 for seeded
+// images to come out the same on WASM as on native builds
+fn rand_index(rng: &mut impl Rng, len: usize) -> usize {
+    rng.gen_range(0..len as u32) as usize
+}
+
+// The (upper left, lower right) corners of a square centered on a point
+const fn selection(re: f64, im: f64, half_extent: f64) -> (Complex<f64>, Complex<f64>) {
+    (
+        Complex {
+            re: re - half_extent,
+            im: im + half_extent,
+        },
+        Complex {
+            re: re + half_extent,
+            im: im - half_extent,
+        },
+    )
+}
+
+// Interesting start points for each of the other formulas, likewise
+pub const BURNING_SHIP_SELECTIONS: [(Complex<f64>, Complex<f64>); 3] = [
+    selection(-1.762, -0.03, 0.05),
+    selection(-0.86, -0.98, 0.1),
+    selection(0.94, -1.34, 0.1),
+];
+
+pub const TRICORN_SELECTIONS: [(Complex<f64>, Complex<f64>); 3] = [
+    selection(-1.22, 0.0, 0.1),
+    selection(-1.4, 0.05, 0.1),
+    selection(0.58, 0.72, 0.1),
+];
+
+pub const CELTIC_SELECTIONS: [(Complex<f64>, Complex<f64>); 2] =
+    [selection(-1.22, 0.36, 0.1), selection(-0.74, 1.08, 0.1)];
+
+pub const CELTIC_MANDELBAR_SELECTIONS: [(Complex<f64>, Complex<f64>); 2] =
+    [selection(-1.3, 0.15, 0.1), selection(-1.0, 0.25, 0.1)];
+
+pub const CELTIC_HEART_SELECTIONS: [(Complex<f64>, Complex<f64>); 2] =
+    [selection(-1.1, 0.15, 0.1), selection(-0.9, 0.1, 0.1)];
+
+// Indexed by the power minus `fractal::MIN_MULTIBROT_POWER`. The points are
+// just outside the main body, among the bulbs and filaments off its boundary
+pub const MULTIBROT_SELECTIONS: [[(Complex<f64>, Complex<f64>); 2]; 6] = [
+    [
+        selection(0.5296, 0.1113, 0.02),
+        selection(0.4241, 0.6138, 0.02),
+    ],
+    [
+        selection(0.6251, 0.1233, 0.02),
+        selection(0.5619, 0.5666, 0.02),
+    ],
+    [
+        selection(0.6717, 0.0905, 0.02),
+        selection(0.7211, 0.2057, 0.02),
+    ],
+    [
+        selection(0.7393, 0.1480, 0.02),
+        selection(0.7505, 0.2933, 0.02),
+    ],
+    [
+        selection(0.7674, 0.1290, 0.02),
+        selection(0.7833, 0.2303, 0.02),
+    ],
+    [
+        selection(0.7827, 0.0954, 0.02),
+        selection(0.8067, 0.2074, 0.02),
+    ],
+];
+
 // Remote-derive serial/deserialize for foreign type num::Complex
 // Potential TODO: Make generic on Complex<T>
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "Complex::<f64>")]
-struct ComplexDef {
+pub(crate) struct ComplexDef {
     re: f64,
     im: f64,
 }
@@ -38,22 +240,566 @@ pub enum ImageTransformFlags {
     INVERT,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// Inclusive limits on the width and height of a rendered image.
+///
+/// Image bounds are read from user-supplied tokens, so anything outside of
+/// these limits is clamped before rendering.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputLimits {
+    pub min_dimension: usize,
+    pub max_dimension: usize,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        Self {
+            min_dimension: MIN_OUTPUT_DIMENSION,
+            max_dimension: MAX_OUTPUT_DIMENSION,
+        }
+    }
+}
+
+impl OutputLimits {
+    pub fn clamp_dimension(&self, dimension: usize) -> usize {
+        dimension.clamp(
+            self.min_dimension,
+            self.max_dimension.max(self.min_dimension),
+        )
+    }
+
+    pub fn contains(&self, dimension: usize) -> bool {
+        (self.min_dimension..=self.max_dimension).contains(&dimension)
+    }
+}
+
+/// Settings for `ImageParams::new_from_rand_with_options`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RandOptions {
+    /// Smallest `interestingness::score` to accept. Candidates are drawn until
+    /// one scores at least this, or `max_attempts` have been scored.
+    pub min_score: f64,
+    /// Most candidates to score, after which the best of them is returned.
+    /// With 0 or 1, the first candidate is returned without being scored.
+    pub max_attempts: usize,
+    /// Most `ImageParams::render_work` of a candidate at its own size.
+    /// Antialiasing is turned off and then the automatic iteration limit is
+    /// lowered to fit, down to the default limit, and candidates that still
+    /// don't fit are skipped unscored.
+    pub max_work: u64,
+    /// Locations to zoom in on, or the built-in `Catalog::builtin()` if unset.
+    /// An empty catalog leaves only the regions found by `discovery`.
+    pub catalog: Option<Arc<Catalog>>,
+}
+
+impl RandOptions {
+    pub fn catalog(&self) -> &Catalog {
+        match &self.catalog {
+            Some(catalog) => catalog,
+            None => Catalog::builtin(),
+        }
+    }
+}
+
+impl Default for RandOptions {
+    fn default() -> Self {
+        Self {
+            min_score: DEFAULT_MIN_SCORE,
+            max_attempts: DEFAULT_MAX_RAND_ATTEMPTS,
+            max_work: DEFAULT_RAND_MAX_WORK,
+            catalog: None,
+        }
+    }
+}
+
+/// Maximum number of iterations to run per point before treating it as part of
+/// the set.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum IterationLimit {
+    Fixed(u32),
+    /// Scale the limit with the magnification of the region, so deep zooms
+    /// keep their detail.
+    Auto,
+}
+
+impl Default for IterationLimit {
+    fn default() -> Self {
+        IterationLimit::Fixed(DEFAULT_ITERATION_LIMIT)
+    }
+}
+
+/// Supersampling, to smooth out aliasing on the fine details of the set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Antialiasing {
+    #[default]
+    Off,
+    /// An n x n grid of samples for every pixel.
+    Grid(u8),
+    /// An n x n grid of samples, only for pixels that differ from their
+    /// neighbors.
+    Adaptive(u8),
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ImageParams {
     pub bounds: (usize, usize),
-    #[serde(with = "ComplexDef")]
     pub upper_left: Complex<f64>,
-    #[serde(with = "ComplexDef")]
     pub lower_right: Complex<f64>,
     pub zoom_factor: f64,
     pub rgb_consts: (u8, u8, u8),
     pub transform_flags: BitFlags<ImageTransformFlags>,
+    pub coloring: ColoringMode,
+    /// Gradient to color with, replacing the `rgb_consts` coloring when set.
+    pub palette: Option<Palette>,
+    pub iteration_limit: IterationLimit,
+    /// Escape radius to use in place of the coloring mode's default.
+    pub bailout_radius: Option<f64>,
+    pub antialiasing: Antialiasing,
+    /// Arbitrary precision view to render in place of `upper_left` and
+    /// `lower_right`, see `set_deep_zoom`.
+    pub deep_zoom: Option<DeepZoom>,
+    pub fractal: FractalKind,
+    pub formula: Formula,
+    /// Source of a formula to iterate in place of `formula`, see
+    /// `set_custom_formula`.
+    pub custom_formula: Option<String>,
+    /// How points in the set are colored.
+    pub interior: InteriorColoring,
+    /// Band of escaped points to leave transparent, as with
+    /// `InteriorColoring::Transparent` for points in the set.
+    pub transparent_band: Option<TransparentBand>,
+}
+
+// The original token layout, which every token starts with
+#[derive(Serialize, Deserialize)]
+struct ImageParamsBase {
+    bounds: (usize, usize),
+    #[serde(with = "ComplexDef")]
+    upper_left: Complex<f64>,
+    #[serde(with = "ComplexDef")]
+    lower_right: Complex<f64>,
+    zoom_factor: f64,
+    rgb_consts: (u8, u8, u8),
+    transform_flags: BitFlags<ImageTransformFlags>,
+}
+
+// Settings added after the original token layout. These are appended to the
+// base layout as a list, only when they differ from their defaults, so older
+// tokens (with no list at all) still decode to the same image.
+//
+// Only ever append new variants here - the variant index is part of the token.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum ImageParamsExtension {
+    Coloring(ColoringMode),
+    Palette(Palette),
+    IterationLimit(IterationLimit),
+    BailoutRadius(f64),
+    Antialiasing(Antialiasing),
+    DeepZoom(DeepZoom),
+    Fractal(FractalKind),
+    Formula(Formula),
+    CustomFormula(String),
+    Interior(InteriorColoring),
+    TransparentBand(TransparentBand),
 }
 
 impl ImageParams {
+    /// Serialize to the compact binary form used in image tokens.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, errors::InvalidImageParams> {
+        let base = ImageParamsBase {
+            bounds: self.bounds,
+            upper_left: self.upper_left,
+            lower_right: self.lower_right,
+            zoom_factor: self.zoom_factor,
+            rgb_consts: self.rgb_consts,
+            transform_flags: self.transform_flags,
+        };
+
+        let mut bytes = bincode::serialize(&base).map_err(Self::bincode_error)?;
+
+        let extensions = self.extensions();
+        if !extensions.is_empty() {
+            bytes.extend(bincode::serialize(&extensions).map_err(Self::bincode_error)?);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Deserialize from the binary form produced by `to_bytes`.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, errors::InvalidImageParams> {
+        // Reading from `&mut &[u8]` advances `bytes` past the base layout
+        let base: ImageParamsBase =
+            bincode::deserialize_from(&mut bytes).map_err(Self::bincode_error)?;
+
+        let mut result = Self {
+            bounds: base.bounds,
+            upper_left: base.upper_left,
+            lower_right: base.lower_right,
+            zoom_factor: base.zoom_factor,
+            rgb_consts: base.rgb_consts,
+            transform_flags: base.transform_flags,
+            coloring: ColoringMode::default(),
+            palette: None,
+            iteration_limit: IterationLimit::default(),
+            bailout_radius: None,
+            antialiasing: Antialiasing::default(),
+            deep_zoom: None,
+            fractal: FractalKind::default(),
+            formula: Formula::default(),
+            custom_formula: None,
+            interior: InteriorColoring::default(),
+            transparent_band: None,
+        };
+
+        if !bytes.is_empty() {
+            let extensions: Vec<ImageParamsExtension> =
+                bincode::deserialize(bytes).map_err(Self::bincode_error)?;
+
+            for extension in extensions {
+                result.apply_extension(extension)?;
+            }
+        }
+
+        // Each setting is within its own limits, but together they can still
+        // ask for far too much work
+        result.validate_work(&OutputLimits::default(), MAX_RENDER_WORK)?;
+
+        Ok(result)
+    }
+
+    /// Encode as a url-safe base64 token, as used in image urls.
+    pub fn to_token(&self) -> Result<String, errors::InvalidImageParams> {
+        Ok(base64::encode_config(self.to_bytes()?, base64::URL_SAFE))
+    }
+
+    /// Decode from a url-safe base64 token produced by `to_token`.
+    pub fn from_token(token: &str) -> Result<Self, errors::InvalidImageParams> {
+        let bytes = base64::decode_config(token, base64::URL_SAFE).map_err(|e| {
+            errors::InvalidImageParams::Default {
+                message: format!("invalid base64: {}", e),
+            }
+        })?;
+
+        Self::from_bytes(&bytes)
+    }
+
+    fn bincode_error(e: bincode::Error) -> errors::InvalidImageParams {
+        errors::InvalidImageParams::Default {
+            message: format!("invalid binary encoding: {}", e),
+        }
+    }
+
+    fn extensions(&self) -> Vec<ImageParamsExtension> {
+        let mut extensions = vec![];
+
+        if self.coloring != ColoringMode::default() {
+            extensions.push(ImageParamsExtension::Coloring(self.coloring));
+        }
+
+        if let Some(palette) = &self.palette {
+            extensions.push(ImageParamsExtension::Palette(palette.clone()));
+        }
+
+        if self.iteration_limit != IterationLimit::default() {
+            extensions.push(ImageParamsExtension::IterationLimit(self.iteration_limit));
+        }
+
+        if let Some(bailout_radius) = self.bailout_radius {
+            extensions.push(ImageParamsExtension::BailoutRadius(bailout_radius));
+        }
+
+        if self.antialiasing != Antialiasing::default() {
+            extensions.push(ImageParamsExtension::Antialiasing(self.antialiasing));
+        }
+
+        if let Some(deep_zoom) = &self.deep_zoom {
+            extensions.push(ImageParamsExtension::DeepZoom(deep_zoom.clone()));
+        }
+
+        if self.fractal != FractalKind::default() {
+            extensions.push(ImageParamsExtension::Fractal(self.fractal));
+        }
+
+        if self.formula != Formula::default() {
+            extensions.push(ImageParamsExtension::Formula(self.formula));
+        }
+
+        if let Some(custom_formula) = &self.custom_formula {
+            extensions.push(ImageParamsExtension::CustomFormula(custom_formula.clone()));
+        }
+
+        if self.interior != InteriorColoring::default() {
+            extensions.push(ImageParamsExtension::Interior(self.interior));
+        }
+
+        if let Some(transparent_band) = self.transparent_band {
+            extensions.push(ImageParamsExtension::TransparentBand(transparent_band));
+        }
+
+        extensions
+    }
+
+    fn apply_extension(
+        &mut self,
+        extension: ImageParamsExtension,
+    ) -> Result<(), errors::InvalidImageParams> {
+        match extension {
+            ImageParamsExtension::Coloring(coloring) => self.set_coloring(coloring)?,
+            ImageParamsExtension::Palette(palette) => {
+                palette.validate()?;
+                self.palette = Some(palette);
+            }
+            ImageParamsExtension::IterationLimit(iteration_limit) => {
+                if let IterationLimit::Fixed(limit) = iteration_limit {
+                    if !(1..=MAX_ITERATION_LIMIT).contains(&limit) {
+                        return Err(errors::InvalidImageParams::Default {
+                            message: format!(
+                                "iteration limit must be from 1 to {}",
+                                MAX_ITERATION_LIMIT
+                            ),
+                        });
+                    }
+                }
+
+                self.iteration_limit = iteration_limit;
+            }
+            ImageParamsExtension::BailoutRadius(bailout_radius) => {
+                if !(MIN_BAILOUT_RADIUS..=MAX_BAILOUT_RADIUS).contains(&bailout_radius) {
+                    return Err(errors::InvalidImageParams::Default {
+                        message: format!(
+                            "bailout radius must be from {} to {}",
+                            MIN_BAILOUT_RADIUS, MAX_BAILOUT_RADIUS
+                        ),
+                    });
+                }
+
+                self.bailout_radius = Some(bailout_radius);
+            }
+            ImageParamsExtension::Antialiasing(antialiasing) => {
+                if let Antialiasing::Grid(n) | Antialiasing::Adaptive(n) = antialiasing {
+                    if !(2..=MAX_SUPERSAMPLING).contains(&n) {
+                        return Err(errors::InvalidImageParams::Default {
+                            message: format!(
+                                "supersampling must be from 2 to {}",
+                                MAX_SUPERSAMPLING
+                            ),
+                        });
+                    }
+                }
+
+                self.antialiasing = antialiasing;
+            }
+            // The corners of a deep zoom are already in the base layout
+            ImageParamsExtension::DeepZoom(deep_zoom) => {
+                deep_zoom.validate()?;
+                *self = self.changed(|img_params| img_params.deep_zoom = Some(deep_zoom))?;
+            }
+            ImageParamsExtension::Fractal(fractal) => self.set_fractal(fractal)?,
+            ImageParamsExtension::Formula(formula) => self.set_formula(formula)?,
+            ImageParamsExtension::CustomFormula(custom_formula) => {
+                self.set_custom_formula(&custom_formula)?;
+            }
+            ImageParamsExtension::Interior(interior) => {
+                interior.validate()?;
+                self.interior = interior;
+            }
+            ImageParamsExtension::TransparentBand(transparent_band) => {
+                transparent_band.validate()?;
+                self.transparent_band = Some(transparent_band);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The output image dimensions, clamped to the default `OutputLimits`.
     pub fn get_bounds(&self) -> (usize, usize) {
-        // Restrict changing from default bounds, for now
-        (OUTPUT_WIDTH, OUTPUT_HEIGHT)
+        self.get_bounds_within(&OutputLimits::default())
+    }
+
+    /// The output image dimensions, clamped to the given `limits`.
+    ///
+    /// `upper_left` and `lower_right` always map to the corners of the image,
+    /// so the framing of the region is the same at every size.
+    pub fn get_bounds_within(&self, limits: &OutputLimits) -> (usize, usize) {
+        (
+            limits.clamp_dimension(self.bounds.0),
+            limits.clamp_dimension(self.bounds.1),
+        )
+    }
+
+    /// The number of iterations to run per point, resolving
+    /// `IterationLimit::Auto` from the size of the region.
+    ///
+    /// Always within `1..=MAX_ITERATION_LIMIT`.
+    pub fn get_iteration_limit(&self) -> usize {
+        let limit = match self.iteration_limit {
+            IterationLimit::Fixed(limit) => limit as f64,
+            IterationLimit::Auto => {
+                let span = match &self.deep_zoom {
+                    Some(deep_zoom) => 2.0 * deep_zoom.half_width.max(deep_zoom.half_height),
+                    None => (self.lower_right.re - self.upper_left.re)
+                        .abs()
+                        .max((self.upper_left.im - self.lower_right.im).abs()),
+                };
+
+                auto_iteration_limit(span)
+            }
+        };
+
+        limit.clamp(1.0, MAX_ITERATION_LIMIT as f64) as usize
+    }
+
+    /// Estimated work of rendering at the bounds within `limits`, in
+    /// iterations of z^2 + c: pixels x samples per pixel x iteration limit x
+    /// the cost of the formula.
+    ///
+    /// This is the worst case, where no sample escapes, and counts every
+    /// pixel as supersampled with `Antialiasing::Adaptive`.
+    pub fn render_work(&self, limits: &OutputLimits) -> u64 {
+        self.work_per_iteration(limits)
+            .saturating_mul(self.get_iteration_limit() as u64)
+    }
+
+    /// Check that `render_work` is at most `max_work`.
+    pub fn validate_work(
+        &self,
+        limits: &OutputLimits,
+        max_work: u64,
+    ) -> Result<(), errors::InvalidImageParams> {
+        let work = self.render_work(limits);
+        if work > max_work {
+            return Err(errors::InvalidImageParams::Default {
+                message: format!(
+                    "too expensive to render, costs {} of at most {}",
+                    work, max_work
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    // `render_work` for an iteration limit of 1
+    fn work_per_iteration(&self, limits: &OutputLimits) -> u64 {
+        let (width, height) = self.get_bounds_within(limits);
+
+        let samples = match self.antialiasing {
+            Antialiasing::Off => 1,
+            Antialiasing::Grid(n) | Antialiasing::Adaptive(n) => (n as u64).pow(2),
+        };
+
+        let formula_cost = match &self.custom_formula {
+            // A formula that doesn't compile fails to render anyway
+            Some(source) => Program::compile(source)
+                .map_or(1, |program| program.cost() as u64 + CUSTOM_FORMULA_OVERHEAD),
+            None => 1,
+        };
+
+        (width as u64 * height as u64)
+            .saturating_mul(samples)
+            .saturating_mul(formula_cost)
+    }
+
+    /// Render the arbitrary precision view `deep_zoom`, also setting
+    /// `upper_left` and `lower_right` to its (f64 rounded) corners.
+    pub fn set_deep_zoom(&mut self, deep_zoom: DeepZoom) -> Result<(), errors::InvalidImageParams> {
+        deep_zoom.validate()?;
+
+        let center = deep_zoom.center_f64()?;
+        let (offset_upper_left, offset_lower_right) = deep_zoom.offset_corners();
+
+        *self = self.changed(|img_params| {
+            img_params.upper_left = center + offset_upper_left;
+            img_params.lower_right = center + offset_lower_right;
+            img_params.deep_zoom = Some(deep_zoom);
+        })?;
+
+        Ok(())
+    }
+
+    /// Iterate the formula `source` (e.g. `z^3 + c*sin(z)`) in place of
+    /// `formula`, checking that it compiles - see `Program::compile` for the
+    /// syntax and limits - and that it can be rendered with the other
+    /// settings.
+    pub fn set_custom_formula(&mut self, source: &str) -> Result<(), errors::InvalidImageParams> {
+        Program::compile(source)?;
+        *self = self.changed(|img_params| img_params.custom_formula = Some(source.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Set `coloring`, checking that it's valid and can be rendered with the
+    /// other settings.
+    pub fn set_coloring(
+        &mut self,
+        coloring: ColoringMode,
+    ) -> Result<(), errors::InvalidImageParams> {
+        coloring.validate()?;
+        *self = self.changed(|img_params| img_params.coloring = coloring)?;
+
+        Ok(())
+    }
+
+    /// Set `fractal`, checking that it's valid and can be rendered with the
+    /// other settings.
+    pub fn set_fractal(&mut self, fractal: FractalKind) -> Result<(), errors::InvalidImageParams> {
+        fractal.validate()?;
+        *self = self.changed(|img_params| img_params.fractal = fractal)?;
+
+        Ok(())
+    }
+
+    /// Set `formula`, checking that it's valid and can be rendered with the
+    /// other settings.
+    pub fn set_formula(&mut self, formula: Formula) -> Result<(), errors::InvalidImageParams> {
+        formula.validate()?;
+        *self = self.changed(|img_params| img_params.formula = formula)?;
+
+        Ok(())
+    }
+
+    // A copy with `change` made to it, if the settings still go together
+    fn changed(&self, change: impl FnOnce(&mut Self)) -> Result<Self, errors::InvalidImageParams> {
+        let mut changed = self.clone();
+        change(&mut changed);
+        changed.validate_combination()?;
+
+        Ok(changed)
+    }
+
+    // Settings that are each valid, but can't be rendered together
+    fn validate_combination(&self) -> Result<(), errors::InvalidImageParams> {
+        // Distance estimation needs the derivative of the formula, which
+        // custom formulas don't have
+        if self.custom_formula.is_some() && self.coloring.needs_derivative() {
+            return Err(errors::InvalidImageParams::Default {
+                message: "distance coloring isn't supported for custom formulas".to_string(),
+            });
+        }
+
+        // The reference orbit of a deep zoom is only iterated for the
+        // Mandelbrot set itself
+        let mandelbrot_set = self.fractal == FractalKind::Mandelbrot
+            && self.formula == Formula::Quadratic
+            && self.custom_formula.is_none();
+        if self.deep_zoom.is_some() && !mandelbrot_set {
+            return Err(errors::InvalidImageParams::Default {
+                message: "deep zoom is only supported for the Mandelbrot set".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The squared escape radius to iterate with.
+    pub fn get_bailout_sqr(&self) -> f64 {
+        match self.bailout_radius {
+            Some(radius) => {
+                let radius = radius.clamp(MIN_BAILOUT_RADIUS, MAX_BAILOUT_RADIUS);
+                radius * radius
+            }
+            None => self.coloring.bailout_sqr(),
+        }
     }
 
     fn get_relative_point(pixel: f64, length: f64, set: (f64, f64)) -> f64 {
@@ -69,23 +815,154 @@ impl ImageParams {
         ]
     }
 
-    fn rand_interesting_selection() -> (Complex<f64>, Complex<f64>) {
-        let mut rng = rand::thread_rng();
+    fn rand_palette(rng: &mut impl Rng, kinds: &[PaletteKind]) -> Palette {
+        Palette {
+            kind: kinds[rand_index(rng, kinds.len())].clone(),
+            cycle_length: rng.gen_range(16..=96),
+            offset: rng.gen(),
+        }
+    }
+
+    fn rand_formula(rng: &mut impl Rng) -> Formula {
+        if !rng.gen_bool(VARIANT_FORMULA_PROBABILITY) {
+            return Formula::Quadratic;
+        }
 
-        INTERESTING_SELECTIONS[rng.gen_range(0..INTERESTING_SELECTIONS.len())]
+        match rng.gen_range(0..6) {
+            0 => Formula::BurningShip,
+            1 => Formula::Tricorn,
+            2 => Formula::Multibrot(
+                rng.gen_range(fractal::MIN_MULTIBROT_POWER..=fractal::MAX_MULTIBROT_POWER),
+            ),
+            3 => Formula::Celtic,
+            4 => Formula::CelticMandelbar,
+            _ => Formula::CelticHeart,
+        }
     }
 
-    // Given a set of image bounds, create a random set of ImageParams
-    pub fn new_from_rand(bounds: (usize, usize)) -> Self {
-        let mut rng = rand::thread_rng();
+    fn rand_coloring(rng: &mut impl Rng, formula: Formula) -> ColoringMode {
+        // Distance estimates for the folding formulas are too rough for lines
+        let suits_line_art = matches!(
+            formula,
+            Formula::Quadratic | Formula::Tricorn | Formula::Multibrot(_)
+        );
 
-        let selection = Self::rand_interesting_selection();
-        let (mut upper_left, mut lower_right) = selection;
+        if suits_line_art && rng.gen_bool(LINE_ART_PROBABILITY) {
+            ColoringMode::Distance {
+                line_width: rng.gen_range(1.0..=2.0),
+                glow: if rng.gen_bool(0.5) {
+                    rng.gen_range(2.0..=8.0)
+                } else {
+                    0.0
+                },
+            }
+        } else if rng.gen_bool(ORBIT_COLORING_PROBABILITY) {
+            Self::rand_orbit_coloring(rng)
+        } else if rng.gen_bool(HISTOGRAM_COLORING_PROBABILITY) {
+            ColoringMode::Histogram
+        } else {
+            ColoringMode::Smooth
+        }
+    }
 
-        let exp = rng.gen_range(1..=10);
-        let zoom_factor = 1.0 / 10.0_f64.powi(exp) * rng.gen_range(1.0..=9.0);
+    fn rand_orbit_coloring(rng: &mut impl Rng) -> ColoringMode {
+        let center = Complex {
+            re: rng.gen_range(-1.0..=1.0),
+            im: rng.gen_range(-1.0..=1.0),
+        };
+        let angle = rng.gen_range(0.0..std::f64::consts::PI);
 
-        debug!("zoom factor {} - {}", exp, zoom_factor);
+        match rng.gen_range(0..5) {
+            0 => ColoringMode::OrbitTrap(OrbitTrap::Point { center }),
+            1 => ColoringMode::OrbitTrap(OrbitTrap::Line {
+                point: center,
+                angle,
+            }),
+            2 => ColoringMode::OrbitTrap(OrbitTrap::Cross { center, angle }),
+            3 => ColoringMode::StripeAverage {
+                density: rng.gen_range(2..=8) as f32,
+            },
+            _ => ColoringMode::CurvatureAverage,
+        }
+    }
+
+    fn interesting_selections(formula: Formula) -> &'static [(Complex<f64>, Complex<f64>)] {
+        match formula {
+            Formula::Quadratic => &INTERESTING_SELECTIONS,
+            Formula::BurningShip => &BURNING_SHIP_SELECTIONS,
+            Formula::Tricorn => &TRICORN_SELECTIONS,
+            Formula::Multibrot(power) => {
+                let i = power.clamp(fractal::MIN_MULTIBROT_POWER, fractal::MAX_MULTIBROT_POWER)
+                    - fractal::MIN_MULTIBROT_POWER;
+                &MULTIBROT_SELECTIONS[i as usize]
+            }
+            Formula::Celtic => &CELTIC_SELECTIONS,
+            Formula::CelticMandelbar => &CELTIC_MANDELBAR_SELECTIONS,
+            Formula::CelticHeart => &CELTIC_HEART_SELECTIONS,
+        }
+    }
+
+    fn rand_interesting_selection(
+        rng: &mut impl Rng,
+        formula: Formula,
+    ) -> (Complex<f64>, Complex<f64>) {
+        let selections = Self::interesting_selections(formula);
+
+        selections[rand_index(rng, selections.len())]
+    }
+
+    // A window framing a minibrot of the lowest period within `half_extent` of
+    // `center`, if it's not much bigger than that
+    fn rand_minibrot_window(
+        rng: &mut impl Rng,
+        center: Complex<f64>,
+        half_extent: f64,
+    ) -> Option<(Complex<f64>, Complex<f64>)> {
+        let max_period = auto_iteration_limit(2.0 * half_extent).min(MAX_ITERATION_LIMIT as f64);
+        let period = landmarks::find_period(center, half_extent, max_period as usize)?;
+        let nucleus = landmarks::find_nucleus(center, period)?;
+
+        let size = landmarks::component_size(nucleus, period);
+        let framed_half_extent = size * rng.gen_range(MINIBROT_FRAMING.0..=MINIBROT_FRAMING.1);
+
+        // The lowest period can be of a component the region is only on the
+        // edge of, far bigger than the region. Newton's method can also land
+        // on another component of the period than the one in the region,
+        // which is just as good a minibrot if it's in scale
+        let in_scale = framed_half_extent <= 4.0 * half_extent
+            && framed_half_extent >= catalog::MIN_LOCATION_HALF_EXTENT;
+        if !in_scale {
+            return None;
+        }
+
+        debug!(
+            "minibrot of period {} at {}, size {:e}",
+            period, nucleus, size
+        );
+
+        Some(selection(nucleus.re, nucleus.im, framed_half_extent))
+    }
+
+    // A window on the boundary of the set, `zoom_factor` times the size of a
+    // view `DISCOVERED_HALF_EXTENT` units from its center to its sides
+    fn rand_zoomed_window(
+        rng: &mut impl Rng,
+        formula: Formula,
+        zoom_factor: f64,
+    ) -> (Complex<f64>, Complex<f64>) {
+        let half_extent = DISCOVERED_HALF_EXTENT * zoom_factor;
+        if let Some(center) = discovery::discover_boundary_point(rng, formula, half_extent) {
+            if formula == Formula::Quadratic && rng.gen_bool(MINIBROT_PROBABILITY) {
+                if let Some(window) = Self::rand_minibrot_window(rng, center, half_extent) {
+                    return window;
+                }
+            }
+
+            return selection(center.re, center.im, half_extent);
+        }
+
+        // Otherwise zoom in on a random pixel of one of the fixed selections
+        let (mut upper_left, mut lower_right) = Self::rand_interesting_selection(rng, formula);
 
         let zfw = OUTPUT_WIDTH as f64 * zoom_factor;
         let zfh = OUTPUT_HEIGHT as f64 * zoom_factor;
@@ -118,13 +995,185 @@ impl ImageParams {
             (upper_left.im, lower_right.im),
         );
 
+        (upper_left, lower_right)
+    }
+
+    // Given a set of image bounds, create a random set of ImageParams, with
+    // the default `RandOptions`
+    pub fn new_from_rand(bounds: (usize, usize)) -> Self {
+        Self::new_from_rand_with_options(bounds, &RandOptions::default())
+    }
+
+    // Same as `new_from_rand`, retrying flat-looking images as set by `options`
+    pub fn new_from_rand_with_options(bounds: (usize, usize), options: &RandOptions) -> Self {
+        Self::new_from_rng(bounds, options, &mut rand::thread_rng())
+    }
+
+    /// The random image for `seed`, with the default `RandOptions`. A seed
+    /// gives the same image every time, on any platform, for as long as the
+    /// generator itself doesn't change.
+    ///
+    /// The seed's 8 little-endian bytes, zero padded to 32, seed a ChaCha8 RNG
+    /// from rand_chacha, which is pinned to a version along with rand. The
+    /// transcendental functions the generator (and the renderer it scores
+    /// candidates with) depends on come from libm, which is pinned too, rather
+    /// than from the platform - see `numeric::ln`.
+    pub fn new_from_seed(bounds: (usize, usize), seed: u64) -> Self {
+        let mut seed_bytes = [0; 32];
+        seed_bytes[..8].copy_from_slice(&seed.to_le_bytes());
+
+        Self::new_from_rng(
+            bounds,
+            &RandOptions::default(),
+            &mut ChaCha8Rng::from_seed(seed_bytes),
+        )
+    }
+
+    /// A random image that only depends on `identity`, e.g. an email address
+    /// or username, so the same image can be shown for it every time without
+    /// storing its token.
+    ///
+    /// The identity is hashed as given with SHA-256 (so normalizing it, like
+    /// trimming and lowercasing an email address, is up to the caller), and
+    /// the 32 byte digest seeds a ChaCha8 RNG, from rand_chacha, to drive the
+    /// same generator as `new_from_rand`. It uses the default `RandOptions`,
+    /// including the built-in catalog, and the default output size, so every
+    /// server gives the same image for an identity however it's configured.
+    pub fn from_identity(identity: &str) -> Self {
+        let seed: [u8; 32] = Sha256::digest(identity.as_bytes()).into();
+        let mut rng = ChaCha8Rng::from_seed(seed);
+
+        Self::new_from_rng(
+            (OUTPUT_WIDTH, OUTPUT_HEIGHT),
+            &RandOptions::default(),
+            &mut rng,
+        )
+    }
+
+    /// Same as `new_from_rand_with_options`, drawing every random choice from
+    /// `rng`, so the same RNG state gives the same image.
+    pub fn new_from_rng(bounds: (usize, usize), options: &RandOptions, rng: &mut impl Rng) -> Self {
+        let catalog = options.catalog();
+
+        // Failing to render a candidate only makes it the least interesting
+        let score = |img_params: &Self| interestingness::score(img_params).unwrap_or(0.0);
+
+        let mut best: Option<(Self, f64)> = None;
+        for attempt in 0..options.max_attempts.max(1) {
+            let mut candidate = Self::rand_candidate(bounds, catalog, rng);
+
+            if !candidate.fit_work(options.max_work) {
+                debug!("random attempt {} is over the work budget", attempt);
+                // Only returned if no attempt fits
+                best.get_or_insert((candidate, f64::NEG_INFINITY));
+                continue;
+            }
+
+            // With a single attempt there's nothing to compare its score to
+            if options.max_attempts <= 1 {
+                return candidate;
+            }
+
+            let candidate_score = score(&candidate);
+            debug!("random attempt {} scored {}", attempt, candidate_score);
+
+            let is_best = match &best {
+                Some((_, best_score)) => candidate_score > *best_score,
+                None => true,
+            };
+            if is_best {
+                best = Some((candidate, candidate_score));
+            }
+
+            if candidate_score >= options.min_score {
+                break;
+            }
+        }
+
+        best.expect("there's always at least one random attempt").0
+    }
+
+    // Bring `render_work` at the image's own size to at most `max_work`, first
+    // by turning off antialiasing, so deep zooms keep the iterations they need,
+    // then by lowering an automatic iteration limit, though never below the
+    // default limit. Returns whether it fits
+    fn fit_work(&mut self, max_work: u64) -> bool {
+        let limits = OutputLimits::default();
+        if self.render_work(&limits) <= max_work {
+            return true;
+        }
+
+        if self.antialiasing != Antialiasing::Off {
+            self.antialiasing = Antialiasing::Off;
+            if self.render_work(&limits) <= max_work {
+                return true;
+            }
+        }
+
+        if self.iteration_limit != IterationLimit::Auto {
+            return false;
+        }
+
+        let limit = max_work / self.work_per_iteration(&limits).max(1);
+        if limit < DEFAULT_ITERATION_LIMIT as u64 {
+            return false;
+        }
+
+        self.iteration_limit = IterationLimit::Fixed(limit.min(MAX_ITERATION_LIMIT as u64) as u32);
+
+        true
+    }
+
+    // The region is picked relative to a fixed OUTPUT_WIDTH x OUTPUT_HEIGHT
+    // frame, so the same random choices give the same region at any `bounds`
+    fn rand_candidate(bounds: (usize, usize), catalog: &Catalog, rng: &mut impl Rng) -> Self {
+        let location: Option<&Location> = if rng.gen_bool(CATALOG_PROBABILITY) {
+            catalog.rand_location(rng)
+        } else {
+            None
+        };
+
+        let formula = match location {
+            Some(location) => location.formula,
+            None => Self::rand_formula(rng),
+        };
+
+        let exp = rng.gen_range(1..=10);
+        let mut zoom_factor = 1.0 / 10.0_f64.powi(exp) * rng.gen_range(1.0..=9.0);
+
+        // Julia sets for `c` near the boundary are framed whole, about the origin
+        let (upper_left, lower_right, fractal) = if let Some(location) = location {
+            debug!("catalog location {}", location.name);
+
+            let half_extent = location.rand_half_extent(rng);
+            zoom_factor = half_extent / DISCOVERED_HALF_EXTENT;
+            let (upper_left, lower_right) =
+                selection(location.center.re, location.center.im, half_extent);
+
+            (upper_left, lower_right, FractalKind::Mandelbrot)
+        } else if formula == Formula::Quadratic && rng.gen_bool(JULIA_PROBABILITY) {
+            let (upper_left, lower_right) = selection(0.0, 0.0, rng.gen_range(1.4..=1.8));
+            let c = fractal::rand_julia_c(rng);
+
+            (upper_left, lower_right, FractalKind::Julia { c })
+        } else {
+            let (upper_left, lower_right) = Self::rand_zoomed_window(rng, formula, zoom_factor);
+
+            (upper_left, lower_right, FractalKind::Mandelbrot)
+        };
+
         let rgb_consts = (
             rng.gen_range::<u8, _>(0..=255),
             rng.gen_range::<u8, _>(0..=255),
             rng.gen_range::<u8, _>(0..=255),
         );
 
-        let random_transform_flags = Self::enabled_rand_transforms().iter().fold(
+        debug!("zoom factor {} - {}", exp, zoom_factor);
+
+        let rand_transforms = location
+            .and_then(|location| location.transforms.clone())
+            .unwrap_or_else(Self::enabled_rand_transforms);
+        let random_transform_flags = rand_transforms.iter().fold(
             BitFlags::EMPTY,
             |acc: BitFlags<_, _>, flag: &ImageTransformFlags| {
                 if rng.gen_bool(0.5) {
@@ -142,6 +1191,22 @@ impl ImageParams {
             zoom_factor,
             rgb_consts,
             transform_flags: random_transform_flags,
+            coloring: Self::rand_coloring(rng, formula),
+            palette: Some(Self::rand_palette(
+                rng,
+                location
+                    .and_then(|location| location.palettes.as_deref())
+                    .unwrap_or(&PaletteKind::BUILTIN),
+            )),
+            iteration_limit: IterationLimit::Auto,
+            bailout_radius: None,
+            antialiasing: Antialiasing::Adaptive(2),
+            deep_zoom: None,
+            fractal,
+            formula,
+            custom_formula: None,
+            interior: InteriorColoring::default(),
+            transparent_band: None,
         }
     }
 }
@@ -151,24 +1216,42 @@ pub enum OverlayImageTypes {
     Profile { width: u32, height: u32 },
 }
 
+impl OverlayImageTypes {
+    /// Name of the smallest overlay asset that covers the overlay dimensions,
+    /// so it only ever needs to be scaled down.
+    pub fn asset_name(&self) -> &'static str {
+        match *self {
+            OverlayImageTypes::Profile { width, height } if width <= 300 && height <= 300 => {
+                "profile_overlay_300x300"
+            }
+            OverlayImageTypes::Profile { .. } => "profile_overlay_600x600",
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ImagePostProcessConfig {
     pub overlay_image_type: Option<OverlayImageTypes>,
+    pub size: Option<usize>,
 }
 
 impl ImagePostProcessConfig {
+    /// Read the post-processing options from query params, with a `size`
+    /// within `limits`.
     pub fn from_query_params(
         param_pairs: &[(impl AsRef<str>, impl AsRef<str>)],
+        limits: &OutputLimits,
     ) -> Result<Self, errors::InvalidPostProcessConfig> {
         let mut result = ImagePostProcessConfig {
             overlay_image_type: None,
+            size: None,
         };
 
-        if param_pairs.len() < 1 {
+        if param_pairs.is_empty() {
             return Ok(result);
         }
 
-        for (k, v) in param_pairs.into_iter() {
+        for (k, v) in param_pairs.iter() {
             match k.as_ref() {
                 "overlay" => match v.as_ref() {
                     "profile" => {
@@ -183,6 +1266,21 @@ impl ImagePostProcessConfig {
                         })
                     }
                 },
+                "s" | "size" => {
+                    let size = v
+                        .as_ref()
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|size| limits.contains(*size))
+                        .ok_or_else(|| errors::InvalidPostProcessConfig::Default {
+                            message: format!(
+                                "Invalid size given, expected a number from {} to {}.",
+                                limits.min_dimension, limits.max_dimension
+                            ),
+                        })?;
+
+                    result.size = Some(size);
+                }
                 _ => {}
             }
         }
@@ -190,6 +1288,26 @@ impl ImagePostProcessConfig {
         Ok(result)
     }
 
+    /// Apply the requested output size to `img_params`, and fit any overlay to
+    /// the resulting image bounds within `limits`.
+    ///
+    /// Should be called before rendering, so the image is rendered natively at
+    /// the requested size rather than rescaled.
+    pub fn apply_to_image_params(&mut self, img_params: &mut ImageParams, limits: &OutputLimits) {
+        if let Some(size) = self.size {
+            img_params.bounds = (size, size);
+        }
+
+        let (width, height) = img_params.get_bounds_within(limits);
+
+        if let Some(OverlayImageTypes::Profile { .. }) = self.overlay_image_type {
+            self.overlay_image_type = Some(OverlayImageTypes::Profile {
+                width: width as u32,
+                height: height as u32,
+            });
+        }
+    }
+
     pub fn should_post_process(&self) -> bool {
         if self.overlay_image_type.is_some() {
             return true;
@@ -198,3 +1316,436 @@ impl ImagePostProcessConfig {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mandelbrot::{render_with_options, RenderOptions};
+    use crate::palette::MAX_CUSTOM_COLORS;
+    use crate::perturbation::{MAX_CENTER_DIGITS, MIN_DEEP_ZOOM_EXTENT};
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+    // A token from before there were any `ImageParamsExtension`s, and an FNV-1a
+    // hash of the pixels it rendered to then
+    const BASELINE_TOKEN: &str =
+        "LAEAAAAAAAAsAQAAAAAAAI_C9Shcj_K_16NwPQrX0z_sUbgehevxv0jhehSuR9E_exSuR-F6lD9Tl-UD";
+    const BASELINE_PIXELS_HASH: u64 = 0xacb6_a1d5_2a96_aa1c;
+
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    fn baseline() -> ImageParams {
+        ImageParams::from_token(BASELINE_TOKEN).unwrap()
+    }
+
+    /// `baseline`'s bytes, followed by `extensions`.
+    fn baseline_bytes_with(extensions: &[ImageParamsExtension]) -> Vec<u8> {
+        let mut bytes = baseline().to_bytes().unwrap();
+        bytes.extend(bincode::serialize(extensions).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn baseline_token_decodes_and_renders_unchanged() {
+        let img_params = baseline();
+
+        assert_eq!(img_params.bounds, (300, 300));
+        assert_eq!(img_params.upper_left, Complex::new(-1.16, 0.31));
+        assert_eq!(img_params.lower_right, Complex::new(-1.12, 0.27));
+        assert_eq!(img_params.rgb_consts, (83, 151, 229));
+        assert_eq!(
+            img_params.transform_flags,
+            ImageTransformFlags::ROT180 | ImageTransformFlags::HUEROT90
+        );
+        assert!(img_params.extensions().is_empty());
+        assert_eq!(img_params.to_token().unwrap(), BASELINE_TOKEN);
+
+        let image = render_with_options(&img_params, &RenderOptions::default()).unwrap();
+        assert_eq!(fnv1a(image.as_raw()), BASELINE_PIXELS_HASH);
+    }
+
+    #[test]
+    fn extensions_round_trip() {
+        let extensions = [
+            ImageParamsExtension::Coloring(ColoringMode::Smooth),
+            ImageParamsExtension::Coloring(ColoringMode::Distance {
+                line_width: 1.5,
+                glow: 4.0,
+            }),
+            ImageParamsExtension::Coloring(ColoringMode::OrbitTrap(OrbitTrap::Line {
+                point: Complex::new(-0.5, 0.25),
+                angle: 0.3,
+            })),
+            ImageParamsExtension::Palette(Palette {
+                kind: PaletteKind::Custom(vec![(255, 0, 0), (0, 0, 255)]),
+                cycle_length: 64,
+                offset: 12,
+            }),
+            ImageParamsExtension::IterationLimit(IterationLimit::Fixed(2000)),
+            ImageParamsExtension::IterationLimit(IterationLimit::Auto),
+            ImageParamsExtension::BailoutRadius(100.0),
+            ImageParamsExtension::Antialiasing(Antialiasing::Adaptive(3)),
+            ImageParamsExtension::DeepZoom(DeepZoom {
+                center_re: "-1.740062382579339905220844167065825638296641720436".to_string(),
+                center_im: "0.028175339779211048992411521144319509687539076742".to_string(),
+                half_width: 1.0e-40,
+                half_height: 1.0e-40,
+            }),
+            ImageParamsExtension::Fractal(FractalKind::Julia {
+                c: Complex::new(-0.8, 0.156),
+            }),
+            ImageParamsExtension::Formula(Formula::Multibrot(5)),
+            ImageParamsExtension::CustomFormula("z^3 + c*sin(z)".to_string()),
+            ImageParamsExtension::Interior(InteriorColoring::Solid { position: 0.4 }),
+            ImageParamsExtension::TransparentBand(TransparentBand {
+                start: 10.0,
+                end: 40.0,
+                softness: 2.0,
+            }),
+        ];
+
+        for extension in extensions {
+            let mut img_params = baseline();
+            img_params.apply_extension(extension.clone()).unwrap();
+            assert_eq!(img_params.extensions(), vec![extension.clone()]);
+
+            let decoded = ImageParams::from_token(&img_params.to_token().unwrap()).unwrap();
+            assert_eq!(decoded, img_params, "{:?} changed", extension);
+        }
+    }
+
+    #[test]
+    fn out_of_range_extensions_are_rejected() {
+        let extensions = [
+            ImageParamsExtension::Coloring(ColoringMode::Distance {
+                line_width: 0.0,
+                glow: 0.0,
+            }),
+            ImageParamsExtension::Coloring(ColoringMode::StripeAverage { density: f32::NAN }),
+            ImageParamsExtension::Palette(Palette {
+                kind: PaletteKind::Fire,
+                cycle_length: 0,
+                offset: 0,
+            }),
+            ImageParamsExtension::Palette(Palette {
+                kind: PaletteKind::Custom(vec![(0, 0, 0); MAX_CUSTOM_COLORS + 1]),
+                cycle_length: 64,
+                offset: 0,
+            }),
+            ImageParamsExtension::IterationLimit(IterationLimit::Fixed(0)),
+            ImageParamsExtension::IterationLimit(IterationLimit::Fixed(MAX_ITERATION_LIMIT + 1)),
+            ImageParamsExtension::BailoutRadius(1.0),
+            ImageParamsExtension::BailoutRadius(f64::NAN),
+            ImageParamsExtension::Antialiasing(Antialiasing::Grid(1)),
+            ImageParamsExtension::Antialiasing(Antialiasing::Adaptive(MAX_SUPERSAMPLING + 1)),
+            ImageParamsExtension::DeepZoom(DeepZoom {
+                center_re: "-0.75".to_string(),
+                center_im: "0.1".to_string(),
+                half_width: 0.0,
+                half_height: 0.0,
+            }),
+            ImageParamsExtension::DeepZoom(DeepZoom {
+                center_re: "not a number".to_string(),
+                center_im: "0.1".to_string(),
+                half_width: 1.0e-20,
+                half_height: 1.0e-20,
+            }),
+            ImageParamsExtension::Fractal(FractalKind::Julia {
+                c: Complex::new(3.0, 0.0),
+            }),
+            ImageParamsExtension::Formula(Formula::Multibrot(2)),
+            ImageParamsExtension::Formula(Formula::Multibrot(9)),
+            ImageParamsExtension::CustomFormula("z^".to_string()),
+            ImageParamsExtension::Interior(InteriorColoring::Solid { position: 2.0 }),
+            ImageParamsExtension::TransparentBand(TransparentBand {
+                start: 40.0,
+                end: 10.0,
+                softness: 0.0,
+            }),
+        ];
+
+        for extension in extensions {
+            assert!(
+                ImageParams::from_bytes(&baseline_bytes_with(std::slice::from_ref(&extension)))
+                    .is_err(),
+                "{:?} was accepted",
+                extension
+            );
+        }
+
+        // Each valid on its own, in either order
+        let distance = ImageParamsExtension::Coloring(ColoringMode::Distance {
+            line_width: 1.0,
+            glow: 0.0,
+        });
+        let custom_formula = ImageParamsExtension::CustomFormula("z^2 + c".to_string());
+        let deep_zoom = ImageParamsExtension::DeepZoom(DeepZoom {
+            center_re: "-0.75".to_string(),
+            center_im: "0.1".to_string(),
+            half_width: 1.0e-20,
+            half_height: 1.0e-20,
+        });
+        let julia = ImageParamsExtension::Fractal(FractalKind::Julia {
+            c: Complex::new(-0.8, 0.156),
+        });
+        let burning_ship = ImageParamsExtension::Formula(Formula::BurningShip);
+        for (a, b) in [
+            (&distance, &custom_formula),
+            (&deep_zoom, &julia),
+            (&deep_zoom, &burning_ship),
+            (&deep_zoom, &custom_formula),
+        ] {
+            for extensions in [[a.clone(), b.clone()], [b.clone(), a.clone()]] {
+                assert!(
+                    ImageParams::from_bytes(&baseline_bytes_with(&extensions)).is_err(),
+                    "{:?} was accepted",
+                    extensions
+                );
+            }
+        }
+
+        // Each within range, but far too much work together
+        let too_expensive = [
+            ImageParamsExtension::IterationLimit(IterationLimit::Fixed(MAX_ITERATION_LIMIT)),
+            ImageParamsExtension::Antialiasing(Antialiasing::Grid(MAX_SUPERSAMPLING)),
+        ];
+        assert!(ImageParams::from_bytes(&baseline_bytes_with(&too_expensive)).is_err());
+    }
+
+    #[test]
+    fn custom_formulas_reject_distance_coloring() {
+        let mut img_params = baseline();
+        img_params.coloring = ColoringMode::Distance {
+            line_width: 1.0,
+            glow: 0.0,
+        };
+
+        assert!(img_params.set_custom_formula("z^2 + c").is_err());
+        assert_eq!(img_params.custom_formula, None);
+
+        img_params.coloring = ColoringMode::Smooth;
+        assert!(img_params.set_custom_formula("z^2 + c").is_ok());
+
+        let distance = ColoringMode::Distance {
+            line_width: 1.0,
+            glow: 0.0,
+        };
+        assert!(img_params.set_coloring(distance).is_err());
+        assert_eq!(img_params.coloring, ColoringMode::Smooth);
+    }
+
+    #[test]
+    fn largest_token_fits_the_server_limit() {
+        let mut img_params = baseline();
+        // The longest encoding of each extension that can be set along with a deep
+        // zoom, within the most work to render
+        img_params.coloring = ColoringMode::OrbitTrap(OrbitTrap::Line {
+            point: Complex::new(-1.5, 0.5),
+            angle: 1.0,
+        });
+        img_params.palette = Some(Palette {
+            kind: PaletteKind::Custom(vec![(255, 255, 255); MAX_CUSTOM_COLORS]),
+            cycle_length: 64,
+            offset: 128,
+        });
+        img_params.iteration_limit = IterationLimit::Fixed(MAX_ITERATION_LIMIT);
+        img_params.bailout_radius = Some(MAX_BAILOUT_RADIUS);
+        img_params.antialiasing = Antialiasing::Adaptive(2);
+        img_params.interior = InteriorColoring::Solid { position: 0.5 };
+        img_params.transparent_band = Some(TransparentBand {
+            start: 1.0,
+            end: 2.0,
+            softness: 1.0,
+        });
+
+        let center = format!("-0.{}", "7".repeat(MAX_CENTER_DIGITS - 1));
+        img_params
+            .set_deep_zoom(DeepZoom {
+                center_re: center.clone(),
+                center_im: center,
+                half_width: MIN_DEEP_ZOOM_EXTENT,
+                half_height: MIN_DEEP_ZOOM_EXTENT,
+            })
+            .unwrap();
+
+        let token = img_params.to_token().unwrap();
+        assert!(token.len() <= MAX_TOKEN_LEN, "{} characters", token.len());
+        assert_eq!(ImageParams::from_token(&token).unwrap(), img_params);
+    }
+
+    #[test]
+    fn deep_random_views_keep_their_iterations() {
+        // As deep as `rand_candidate` zooms, with the settings it picks
+        let mut img_params = baseline();
+        (img_params.upper_left, img_params.lower_right) =
+            selection(-0.75, 0.1, DISCOVERED_HALF_EXTENT * 1.0e-10);
+        img_params.iteration_limit = IterationLimit::Auto;
+        img_params.antialiasing = Antialiasing::Adaptive(2);
+        let auto_limit = img_params.get_iteration_limit();
+
+        assert!(img_params.fit_work(DEFAULT_RAND_MAX_WORK));
+        assert!(img_params.render_work(&OutputLimits::default()) <= DEFAULT_RAND_MAX_WORK);
+        assert_eq!(img_params.get_iteration_limit(), auto_limit);
+    }
+
+    // The candidates `new_from_rng` draws from `rng` with `options`, and their
+    // scores
+    fn rand_candidates(
+        options: &RandOptions,
+        mut rng: impl Rng,
+        count: usize,
+    ) -> Vec<(ImageParams, f64)> {
+        (0..count)
+            .map(|_| {
+                let mut candidate = ImageParams::rand_candidate(
+                    (OUTPUT_WIDTH, OUTPUT_HEIGHT),
+                    options.catalog(),
+                    &mut rng,
+                );
+                let score = match candidate.fit_work(options.max_work) {
+                    true => interestingness::score(&candidate).unwrap_or(0.0),
+                    false => f64::NEG_INFINITY,
+                };
+
+                (candidate, score)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_images_retry_until_they_score_enough() {
+        let rng = ChaCha8Rng::seed_from_u64(7);
+        let candidates = rand_candidates(&RandOptions::default(), rng.clone(), 8);
+
+        // Only met by a later candidate than the first
+        let min_score = candidates[0].1 + 0.01;
+        let first_met = candidates
+            .iter()
+            .position(|(_, score)| *score >= min_score)
+            .expect("a candidate scores higher than the first");
+
+        let options = RandOptions {
+            min_score,
+            ..RandOptions::default()
+        };
+        let img_params =
+            ImageParams::new_from_rng((OUTPUT_WIDTH, OUTPUT_HEIGHT), &options, &mut rng.clone());
+        assert_eq!(img_params, candidates[first_met].0);
+    }
+
+    #[test]
+    fn random_images_settle_for_the_best_attempt() {
+        let rng = ChaCha8Rng::seed_from_u64(7);
+        let options = RandOptions {
+            // More than any image scores
+            min_score: 2.0,
+            max_attempts: 4,
+            ..RandOptions::default()
+        };
+        let candidates = rand_candidates(&options, rng.clone(), options.max_attempts);
+        let (best, _) = candidates
+            .into_iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+
+        let img_params =
+            ImageParams::new_from_rng((OUTPUT_WIDTH, OUTPUT_HEIGHT), &options, &mut rng.clone());
+        assert_eq!(img_params, best);
+    }
+
+    #[test]
+    fn query_sizes_are_within_limits() {
+        let limits = OutputLimits {
+            min_dimension: 32,
+            max_dimension: 4096,
+        };
+        let params = |size: &'static str| [("overlay", "profile"), ("s", size)];
+
+        assert!(ImagePostProcessConfig::from_query_params(&params("3000"), &limits).is_ok());
+        assert!(ImagePostProcessConfig::from_query_params(
+            &params("3000"),
+            &OutputLimits::default()
+        )
+        .is_err());
+        assert!(ImagePostProcessConfig::from_query_params(&params("16"), &limits).is_err());
+
+        // The overlay fits the bounds, as clamped to the limits
+        let mut img_params = baseline();
+        img_params.bounds = (8, 8);
+        let mut config =
+            ImagePostProcessConfig::from_query_params(&[("overlay", "profile")], &limits).unwrap();
+        config.apply_to_image_params(&mut img_params, &limits);
+        assert_eq!(
+            config.overlay_image_type,
+            Some(OverlayImageTypes::Profile {
+                width: 32,
+                height: 32
+            })
+        );
+    }
+
+    #[test]
+    fn multibrot_selections_are_detailed() {
+        for (i, selections) in MULTIBROT_SELECTIONS.iter().enumerate() {
+            let formula = Formula::Multibrot(fractal::MIN_MULTIBROT_POWER + i as u8);
+
+            for &(upper_left, lower_right) in selections {
+                let img_params = ImageParams {
+                    upper_left,
+                    lower_right,
+                    formula,
+                    coloring: ColoringMode::Smooth,
+                    ..baseline()
+                };
+                let score = interestingness::score(&img_params).unwrap();
+
+                assert!(
+                    score >= 0.3,
+                    "{:?} around {} scores {}",
+                    formula,
+                    (upper_left + lower_right) / 2.0,
+                    score
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn encoded_identities_match_decoded() {
+        let identities = [
+            "jörg".to_string(),
+            "jane doe".to_string(),
+            "a+b@example.com".to_string(),
+            "100%".to_string(),
+            // 200 bytes decoded, but 600 encoded
+            "ö".repeat(100),
+        ];
+
+        for identity in identities {
+            let segment = format!("{}.png", identity);
+            let encoded = utf8_percent_encode(&segment, NON_ALPHANUMERIC).to_string();
+
+            assert_eq!(identity_from_path(&segment), Ok(identity.as_str()));
+            assert_eq!(identity_from_encoded_path(&encoded), Ok(identity.clone()));
+            assert_eq!(
+                ImageParams::from_identity(&identity_from_encoded_path(&encoded).unwrap()),
+                ImageParams::from_identity(identity_from_path(&segment).unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn identity_length_is_measured_decoded() {
+        // 258 bytes decoded
+        let identity = "ö".repeat(129);
+        let encoded = utf8_percent_encode(&identity, NON_ALPHANUMERIC).to_string();
+
+        assert!(identity_from_path(&identity).is_err());
+        assert!(identity_from_encoded_path(&encoded).is_err());
+        assert!(identity_from_encoded_path("%20%20.png").is_err());
+        assert!(identity_from_encoded_path("%FF").is_err());
+    }
+}
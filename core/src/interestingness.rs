@@ -0,0 +1,178 @@
+use enumflags2::BitFlags;
+use image::{Rgba, RgbaImage};
+use std::collections::BTreeMap;
+
+use crate::coloring::InteriorColoring;
+use crate::errors;
+use crate::image_params::{Antialiasing, ImageParams};
+use crate::mandelbrot::{self, RenderOptions, ADAPTIVE_AA_THRESHOLD};
+use crate::numeric;
+
+/// Size of the longer side of the preview an image is scored from, in pixels.
+pub const SCORE_RENDER_SIZE: usize = 64;
+
+// Edge density at and above which an image counts as fully detailed
+const TARGET_EDGE_DENSITY: f64 = 0.15;
+// Fraction of the image the set can take up before it's penalized
+const FREE_INTERIOR_RATIO: f64 = 0.5;
+// Bits per channel colors are quantized to for `color_entropy`
+const ENTROPY_CHANNEL_BITS: u32 = 4;
+
+/// Measures of how much is going on in an image, from a low resolution
+/// preview of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interestingness {
+    /// Fraction of neighboring pixels that differ noticeably, from 0 to 1.
+    pub edge_density: f64,
+    /// Entropy of the (quantized) colors, relative to the most the preview
+    /// could have, from 0 to 1.
+    pub color_entropy: f64,
+    /// Fraction of the image in the set, from 0 to 1.
+    pub interior_ratio: f64,
+}
+
+impl Interestingness {
+    /// Measure `img_params`, rendering a preview at most `SCORE_RENDER_SIZE`
+    /// pixels wide or high.
+    pub fn measure(img_params: &ImageParams) -> Result<Self, errors::ImageProcessingError> {
+        let (width, height) = img_params.get_bounds();
+        let scale = SCORE_RENDER_SIZE as f64 / width.max(height) as f64;
+
+        // Transparent interiors tell the set apart whatever it's colored with
+        let preview_params = ImageParams {
+            bounds: (
+                ((width as f64 * scale).round() as usize).max(1),
+                ((height as f64 * scale).round() as usize).max(1),
+            ),
+            transform_flags: BitFlags::EMPTY,
+            antialiasing: Antialiasing::Off,
+            interior: InteriorColoring::Transparent,
+            transparent_band: None,
+            ..img_params.clone()
+        };
+        let preview = mandelbrot::render_with_options(&preview_params, &RenderOptions::default())?;
+
+        Ok(Self::of_preview(&preview))
+    }
+
+    fn of_preview(preview: &RgbaImage) -> Self {
+        let (width, height) = preview.dimensions();
+        let pixels = (width * height) as f64;
+        let is_interior = |pixel: &Rgba<u8>| pixel.0[3] == 0;
+
+        let mut edges = 0;
+        let mut neighbors = 0;
+        for (x, y, pixel) in preview.enumerate_pixels() {
+            let right = (x + 1 < width).then(|| preview.get_pixel(x + 1, y));
+            let below = (y + 1 < height).then(|| preview.get_pixel(x, y + 1));
+
+            for neighbor in [right, below].into_iter().flatten() {
+                neighbors += 1;
+                if mandelbrot::color_distance(pixel, neighbor) > ADAPTIVE_AA_THRESHOLD {
+                    edges += 1;
+                }
+            }
+        }
+
+        // The interior all lands in one bin, being the same transparent color.
+        // Bins are summed in a fixed order, as float addition isn't associative
+        let mut bins: BTreeMap<[u8; 4], usize> = BTreeMap::new();
+        for pixel in preview.pixels() {
+            let quantized = pixel.0.map(|channel| channel >> (8 - ENTROPY_CHANNEL_BITS));
+            *bins.entry(quantized).or_default() += 1;
+        }
+        let entropy: f64 = bins
+            .values()
+            .map(|&count| {
+                let p = count as f64 / pixels;
+                -p * numeric::log2(p)
+            })
+            .sum();
+        let max_entropy = numeric::log2(pixels).min((3 * ENTROPY_CHANNEL_BITS) as f64);
+
+        Self {
+            edge_density: edges as f64 / neighbors.max(1) as f64,
+            color_entropy: if max_entropy > 0.0 {
+                (entropy / max_entropy).min(1.0)
+            } else {
+                0.0
+            },
+            interior_ratio: preview.pixels().filter(|pixel| is_interior(pixel)).count() as f64
+                / pixels,
+        }
+    }
+
+    /// A single score from 0 (flat) to 1: the color entropy, reduced for
+    /// images with few edges or mostly in the set.
+    pub fn score(&self) -> f64 {
+        let edges = (self.edge_density / TARGET_EDGE_DENSITY).min(1.0);
+        let exterior = ((1.0 - self.interior_ratio) / (1.0 - FREE_INTERIOR_RATIO)).min(1.0);
+
+        self.color_entropy * edges * exterior
+    }
+}
+
+/// `Interestingness::score` of `img_params`.
+pub fn score(img_params: &ImageParams) -> Result<f64, errors::ImageProcessingError> {
+    Ok(Interestingness::measure(img_params)?.score())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coloring::ColoringMode;
+    use crate::mandelbrot::tests::{seahorse_valley, view, whole_set};
+    use image::ImageBuffer;
+
+    #[test]
+    fn uniform_images_score_below_detailed_ones() {
+        let uniform =
+            Interestingness::of_preview(&ImageBuffer::from_pixel(32, 32, Rgba([40, 80, 120, 255])));
+        assert_eq!(uniform.edge_density, 0.0);
+        assert_eq!(uniform.score(), 0.0);
+
+        // Far outside the set, where every point escapes straight away
+        let flat = score(&view((10.0, 10.0), (11.0, 9.0), (64, 64), 255)).unwrap();
+
+        let mut detailed = seahorse_valley((64, 64));
+        detailed.coloring = ColoringMode::Smooth;
+        let detailed = score(&detailed).unwrap();
+
+        assert!(flat < 0.05, "{}", flat);
+        assert!(detailed > 0.3, "{}", detailed);
+    }
+
+    #[test]
+    fn interior_images_score_low() {
+        // Inside the main cardioid
+        let interior =
+            Interestingness::measure(&view((-0.2, 0.2), (0.2, -0.2), (64, 64), 255)).unwrap();
+
+        assert_eq!(interior.interior_ratio, 1.0);
+        assert_eq!(interior.score(), 0.0);
+    }
+
+    #[test]
+    fn scores_are_in_range() {
+        let mut views = vec![
+            whole_set((64, 48)),
+            seahorse_valley((64, 64)),
+            view((10.0, 10.0), (11.0, 9.0), (64, 64), 255),
+        ];
+        let mut smooth = whole_set((48, 64));
+        smooth.coloring = ColoringMode::Smooth;
+        views.push(smooth);
+
+        for img_params in views {
+            let measured = Interestingness::measure(&img_params).unwrap();
+            for measure in [
+                measured.edge_density,
+                measured.color_entropy,
+                measured.interior_ratio,
+                measured.score(),
+            ] {
+                assert!((0.0..=1.0).contains(&measure), "{:?}", measured);
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use num::{Complex, Zero};
+
+use crate::numeric;
+
+// Special points of the (z^2 + c) Mandelbrot set to zoom in on: the nuclei at
+// the centers of its hyperbolic components (the cardioids and disks of the set
+// and its minibrots), and the Misiurewicz points where its filaments branch
+// and spiral
+
+/// Most Newton's method steps taken to find a point before giving up.
+pub const MAX_NEWTON_STEPS: usize = 64;
+
+// A Newton step this small, relative to the point, counts as converged
+const NEWTON_TOLERANCE: f64 = 1.0e-14;
+// Orbit points this close together (or to 0) count as the same point, for
+// telling apart points of a lower period or preperiod
+const DISTINCT_TOLERANCE: f64 = 1.0e-10;
+// Magnitude past which an orbit has escaped
+const ESCAPE_RADIUS: f64 = 2.0;
+
+/// The lowest period of a hyperbolic component with a nucleus within `radius`
+/// of `center`, if any is up to `max_period`.
+///
+/// Iterates the whole disk around `center` at once, as a center orbit plus a
+/// bound on how far any other orbit is from it, and returns the first
+/// iteration where the disk's image surrounds 0, as a nucleus of that period
+/// is the point whose orbit comes back to 0.
+pub fn find_period(center: Complex<f64>, radius: f64, max_period: usize) -> Option<usize> {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    let mut z_radius = 0.0;
+
+    for period in 1..=max_period {
+        // |(z + e)^2 + (c + d) - (z^2 + c)| <= 2|z||e| + |e|^2 + |d|
+        z_radius = 2.0 * numeric::norm(z) * z_radius + z_radius * z_radius + radius;
+        z = z * z + center;
+
+        if numeric::norm(z) <= z_radius {
+            return Some(period);
+        }
+        if numeric::norm(z) - z_radius > ESCAPE_RADIUS {
+            return None;
+        }
+    }
+
+    None
+}
+
+/// The nucleus of a hyperbolic component of exactly `period` near `guess`,
+/// i.e. the `c` for which 0 is periodic, found with Newton's method.
+///
+/// Returns `None` if it doesn't converge, or converges to the nucleus of a
+/// component of a lower period.
+pub fn find_nucleus(guess: Complex<f64>, period: usize) -> Option<Complex<f64>> {
+    if period == 0 {
+        return None;
+    }
+
+    // Solve z_period(c) = 0
+    let c = newton(guess, |c| {
+        let orbit = orbit(c, period);
+        orbit[period]
+    })?;
+
+    // The roots include the nuclei of every period dividing `period`
+    let orbit = orbit(c, period);
+    let lower_period = (1..period)
+        .filter(|lower| period.is_multiple_of(*lower))
+        .any(|lower| is_same(orbit[lower].0, Complex::zero()));
+
+    (!lower_period).then_some(c)
+}
+
+/// The Misiurewicz point near `guess` whose critical orbit (the orbit of 0)
+/// lands on a cycle of exactly `period` after exactly `preperiod` iterations,
+/// found with Newton's method.
+///
+/// Returns `None` for a `preperiod` under 2 (which would be a nucleus), if it
+/// doesn't converge, or if it converges to a point of a lower preperiod or
+/// period, or to a nucleus.
+pub fn find_misiurewicz(
+    guess: Complex<f64>,
+    preperiod: usize,
+    period: usize,
+) -> Option<Complex<f64>> {
+    if preperiod < 2 || period == 0 {
+        return None;
+    }
+
+    // Solve z_(preperiod + period)(c) - z_preperiod(c) = 0
+    let c = newton(guess, |c| {
+        let orbit = orbit(c, preperiod + period);
+        difference(orbit[preperiod + period], orbit[preperiod])
+    })?;
+
+    // The roots include every point with a lower preperiod (including the
+    // nuclei, with none), or with a period dividing `period`
+    let orbit = orbit(c, preperiod + period);
+    let lower_preperiod = is_same(orbit[preperiod - 1 + period].0, orbit[preperiod - 1].0);
+    let lower_period = (1..period)
+        .filter(|lower| period.is_multiple_of(*lower))
+        .any(|lower| is_same(orbit[preperiod + lower].0, orbit[preperiod].0));
+
+    (!lower_preperiod && !lower_period).then_some(c)
+}
+
+/// Rough size of the hyperbolic component of `period` with its nucleus at
+/// `nucleus`, relative to the main cardioid (which is 1 wide, from -0.75 to
+/// 0.25). The period 2 disk, 0.5 wide, is 0.5.
+///
+/// A minibrot is near enough a copy of the whole set scaled by the size of its
+/// cardioid, so is about 2.25 times its size wide, antenna included.
+pub fn component_size(nucleus: Complex<f64>, period: usize) -> f64 {
+    // From the linearized return map of the component's cycle: with `l` the
+    // derivative of the cycle so far, and `b` the sum of 1/l
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    let mut l = Complex { re: 1.0, im: 0.0 };
+    let mut b = Complex { re: 1.0, im: 0.0 };
+
+    for _ in 1..period {
+        z = z * z + nucleus;
+        l = 2.0 * z * l;
+        b += l.inv();
+    }
+
+    numeric::norm((b * l * l).inv())
+}
+
+// Orbit of 0, z_0 through z_len, paired with the derivatives with respect to c
+fn orbit(c: Complex<f64>, len: usize) -> Vec<(Complex<f64>, Complex<f64>)> {
+    let mut orbit = Vec::with_capacity(len + 1);
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    let mut dz = Complex { re: 0.0, im: 0.0 };
+    orbit.push((z, dz));
+
+    for _ in 0..len {
+        // d/dc (z^2 + c) = 2 z dz/dc + 1
+        dz = 2.0 * z * dz + 1.0;
+        z = z * z + c;
+        orbit.push((z, dz));
+    }
+
+    orbit
+}
+
+// Difference of two values paired with their derivatives
+fn difference(
+    a: (Complex<f64>, Complex<f64>),
+    b: (Complex<f64>, Complex<f64>),
+) -> (Complex<f64>, Complex<f64>) {
+    (a.0 - b.0, a.1 - b.1)
+}
+
+// Newton's method on `f`, which returns f(c) and f'(c)
+fn newton(
+    guess: Complex<f64>,
+    f: impl Fn(Complex<f64>) -> (Complex<f64>, Complex<f64>),
+) -> Option<Complex<f64>> {
+    let mut c = guess;
+
+    for _ in 0..MAX_NEWTON_STEPS {
+        let (value, derivative) = f(c);
+        let step = value / derivative;
+        if !step.re.is_finite() || !step.im.is_finite() {
+            return None;
+        }
+
+        c -= step;
+
+        if numeric::norm(step) <= NEWTON_TOLERANCE * numeric::norm(c).max(1.0) {
+            return Some(c);
+        }
+    }
+
+    None
+}
+
+fn is_same(a: Complex<f64>, b: Complex<f64>) -> bool {
+    numeric::norm(a - b) <= DISTINCT_TOLERANCE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_period_3_nucleus() {
+        // The nucleus of the largest minibrot on the antenna
+        let nucleus = find_nucleus(Complex::new(-1.75, 0.0), 3).unwrap();
+
+        assert!(
+            (nucleus.re - -1.754_877_666_246_693).abs() < 1.0e-12,
+            "{}",
+            nucleus
+        );
+        assert!(nucleus.im.abs() < 1.0e-12, "{}", nucleus);
+    }
+
+    #[test]
+    fn finds_the_period_of_the_period_2_disk() {
+        let disk = Complex::new(-1.0, 0.0);
+
+        assert_eq!(find_period(disk, 0.05, 16), Some(2));
+        assert_eq!(find_period(Complex::new(0.02, 0.01), 0.05, 16), Some(1));
+        // Outside of the set, escaping before reaching any nucleus
+        assert_eq!(find_period(Complex::new(1.0, 1.0), 0.05, 16), None);
+    }
+
+    #[test]
+    fn rejects_roots_of_lower_periods() {
+        // z_4 is also 0 at the period 2 nucleus, and z_2 at the period 1 one
+        assert_eq!(find_nucleus(Complex::new(-1.01, 0.01), 4), None);
+        assert_eq!(find_nucleus(Complex::new(0.01, 0.01), 2), None);
+
+        // Whereas the same guess at its own period converges
+        let nucleus = find_nucleus(Complex::new(-1.01, 0.01), 2).unwrap();
+        assert!(is_same(nucleus, Complex::new(-1.0, 0.0)), "{}", nucleus);
+    }
+}
@@ -1,4 +1,14 @@
+pub mod catalog;
+pub mod coloring;
+pub mod discovery;
 pub mod errors;
+pub mod expression;
+pub mod fractal;
 pub mod image_params;
+pub mod interestingness;
+pub mod landmarks;
 pub mod mandelbrot;
+pub mod numeric;
+pub mod palette;
+pub mod perturbation;
 pub mod post_processing;
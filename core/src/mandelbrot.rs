@@ -1,5 +1,14 @@
+use crate::coloring::{
+    ColorScheme, InteriorColoring, IterationHistogram, OrbitColoring, OrbitStats,
+};
 use crate::errors;
-use crate::image_params::{ImageParams, ImageTransformFlags, OUTPUT_HEIGHT, OUTPUT_WIDTH};
+use crate::expression::Program;
+use crate::fractal::{Formula, FractalKind};
+use crate::image_params::{
+    Antialiasing, ImageParams, ImageTransformFlags, OutputLimits, MAX_RENDER_WORK,
+};
+use crate::numeric::{self, DoubleDouble, Precision, RenderFloat};
+use crate::perturbation::ReferenceOrbit;
 use cfg_if::cfg_if;
 use image::codecs::png::PngEncoder;
 use image::imageops;
@@ -8,81 +17,959 @@ use image::ImageBuffer;
 use image::ImageEncoder;
 use image::Rgba;
 use image::RgbaImage;
+use log::debug;
 use num::Complex;
 
-/// Try to determine if `c` is in the Mandelbrot set, using at most `limit`
-/// iterations to decide.
+#[cfg(feature = "simd")]
+mod simd;
+
+/// The point at which the orbit of some `c` left the bailout radius.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Escape {
+    /// Number of iterations it took to escape.
+    pub(crate) count: usize,
+    /// The first value of the orbit outside of the bailout radius.
+    z: Complex<f64>,
+    /// Derivative of `z` with respect to the point, when
+    /// `EscapeConfig::track_derivative` is set.
+    derivative: Option<Complex<f64>>,
+    /// Statistics of the orbit, when `EscapeConfig::orbit_coloring` is set.
+    orbit: Option<OrbitStats>,
+}
+
+/// What's known about the orbit of a point that never left the bailout radius.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Bounded {
+    /// The last value of the orbit, when `EscapeConfig::track_interior` is set.
+    z: Option<Complex<f64>>,
+    /// Period of the cycle the orbit settled into, when
+    /// `EscapeConfig::find_period` is set and it settled into one.
+    period: Option<usize>,
+}
+
+/// The result of iterating a point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Outcome {
+    /// The orbit left the bailout radius.
+    Escaped(Escape),
+    /// The orbit never left, so the point is (likely) a member of the set.
+    Bounded(Bounded),
+}
+
+impl Outcome {
+    pub(crate) fn escaped(self) -> Option<Escape> {
+        match self {
+            Outcome::Escaped(escape) => Some(escape),
+            Outcome::Bounded(_) => None,
+        }
+    }
+}
+
+impl Default for Outcome {
+    fn default() -> Self {
+        Outcome::Bounded(Bounded::default())
+    }
+}
+
+/// Settings for the escape-time loop, shared by every point in an image.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct EscapeConfig {
+    /// Maximum number of iterations before treating a point as a member.
+    pub(crate) limit: usize,
+    /// Squared radius of the circle a point's orbit must leave to escape.
+    bailout_sqr: f64,
+    /// Orbit of the view's center, when rendering a deep zoom.
+    reference: Option<ReferenceOrbit>,
+    fractal: FractalKind,
+    formula: Formula,
+    /// Compiled `custom_formula`, iterated in place of `formula` when set.
+    custom_formula: Option<Program>,
+    /// Skip iterating points in the main cardioid and period 2 bulb.
+    bulb_check: bool,
+    /// Stop iterating orbits that return to an earlier value.
+    periodicity_check: bool,
+    /// Carry the derivative of each orbit along with it, for distance
+    /// estimation.
+    track_derivative: bool,
+    /// Gather `OrbitStats` for this along each orbit, when set.
+    orbit_coloring: Option<OrbitColoring>,
+    /// Keep the last value of the orbits that don't escape.
+    track_interior: bool,
+    /// Find the period of the cycle each orbit that doesn't escape settles
+    /// into.
+    find_period: bool,
+}
+
+impl EscapeConfig {
+    fn from_params(
+        img_params: &ImageParams,
+        interior_checks: bool,
+    ) -> Result<Self, errors::ImageProcessingError> {
+        let limit = img_params.get_iteration_limit();
+        let bailout_sqr = img_params.get_bailout_sqr();
+
+        let reference = match &img_params.deep_zoom {
+            // The reference orbit is only iterated for the Mandelbrot set
+            Some(_)
+                if img_params.fractal != FractalKind::Mandelbrot
+                    || img_params.formula != Formula::Quadratic
+                    || img_params.custom_formula.is_some() =>
+            {
+                return Err(errors::ImageProcessingError::Default {
+                    message: "Deep zoom is only supported for the Mandelbrot set".to_string(),
+                })
+            }
+            Some(deep_zoom) => Some(ReferenceOrbit::new(deep_zoom, limit, bailout_sqr).map_err(
+                |e| errors::ImageProcessingError::Default {
+                    message: format!("Failed to compute deep zoom reference orbit: {}", e),
+                },
+            )?),
+            None => None,
+        };
+
+        let track_derivative = img_params.coloring.needs_derivative();
+        if track_derivative && img_params.custom_formula.is_some() {
+            return Err(errors::ImageProcessingError::Default {
+                message: "Distance estimation isn't supported for custom formulas".to_string(),
+            });
+        }
+
+        let custom_formula = match &img_params.custom_formula {
+            Some(source) => Some(Program::compile(source).map_err(|e| {
+                errors::ImageProcessingError::Default {
+                    message: format!("Failed to compile custom formula: {}", e),
+                }
+            })?),
+            None => None,
+        };
+
+        // Both interior modes need the orbit itself
+        let track_interior = img_params.interior.needs_orbit() && reference.is_none();
+        let find_period = img_params.interior == InteriorColoring::Period && track_interior;
+
+        // The bulbs are only known for the Mandelbrot set itself, and deep zooms
+        // iterate offsets rather than points
+        let bulb_check = interior_checks
+            && !track_interior
+            && reference.is_none()
+            && custom_formula.is_none()
+            && img_params.fractal == FractalKind::Mandelbrot
+            && img_params.formula == Formula::Quadratic;
+
+        Ok(Self {
+            limit,
+            bailout_sqr,
+            periodicity_check: interior_checks && reference.is_none(),
+            reference,
+            fractal: img_params.fractal,
+            formula: img_params.formula,
+            custom_formula,
+            bulb_check,
+            track_derivative,
+            orbit_coloring: img_params.coloring.orbit_coloring(),
+            track_interior,
+            find_period,
+        })
+    }
+
+    /// Only the escape counts of the Mandelbrot set of `formula`, skipping
+    /// what's known to be in it, for sampling the set without rendering it.
+    pub(crate) fn counts_only(formula: Formula, limit: usize, bailout_sqr: f64) -> Self {
+        Self {
+            limit,
+            bailout_sqr,
+            reference: None,
+            fractal: FractalKind::Mandelbrot,
+            formula,
+            custom_formula: None,
+            bulb_check: formula == Formula::Quadratic,
+            periodicity_check: true,
+            track_derivative: false,
+            orbit_coloring: None,
+            track_interior: false,
+            find_period: false,
+        }
+    }
+
+    /// Whether the regions of each escape count are connected, with no holes,
+    /// as they are for the Mandelbrot sets of the holomorphic formulas
+    /// (`z^n + c`).
+    fn has_connected_level_sets(&self) -> bool {
+        let holomorphic = matches!(self.formula, Formula::Quadratic | Formula::Multibrot(_))
+            && self.custom_formula.is_none();
+
+        holomorphic && self.fractal == FractalKind::Mandelbrot
+    }
+}
+
+/// Tracks whatever else than the escape count `EscapeConfig` asks for, along an
+/// orbit.
+struct OrbitTracker<'a> {
+    config: &'a EscapeConfig,
+    derivative: Complex<f64>,
+    stats: OrbitStats,
+    steps: usize,
+}
+
+impl<'a> OrbitTracker<'a> {
+    /// A tracker for `config`, if it asks for anything to be tracked.
+    fn new(config: &'a EscapeConfig) -> Option<Self> {
+        (config.track_derivative || config.orbit_coloring.is_some()).then(|| Self {
+            config,
+            derivative: config.fractal.initial_derivative(),
+            stats: OrbitStats::default(),
+            steps: 0,
+        })
+    }
+
+    /// Track the value `z` of the orbit, just before it's iterated.
+    fn step(&mut self, z: Complex<f64>) {
+        if self.config.track_derivative {
+            self.derivative = self.config.formula.derivative(z, self.derivative)
+                + self.config.fractal.constant_derivative();
+        }
+
+        // The starting value is the same for every Mandelbrot orbit, so it
+        // isn't part of the stats
+        if let Some(orbit_coloring) = &self.config.orbit_coloring {
+            if self.steps > 0 {
+                self.stats.record(orbit_coloring, z);
+            }
+        }
+
+        self.steps += 1;
+    }
+
+    /// The escape of an orbit after `count` iterations, at `z`, with whatever
+    /// `tracker` tracked along it.
+    fn escape(tracker: Option<Self>, count: usize, z: Complex<f64>) -> Escape {
+        let mut escape = Escape {
+            count,
+            z,
+            derivative: None,
+            orbit: None,
+        };
+
+        if let Some(mut tracker) = tracker {
+            let config = tracker.config;
+            if let Some(orbit_coloring) = &config.orbit_coloring {
+                if count > 0 {
+                    tracker.stats.record(orbit_coloring, z);
+                }
+            }
+
+            escape.derivative = config.track_derivative.then_some(tracker.derivative);
+            escape.orbit = config.orbit_coloring.is_some().then_some(tracker.stats);
+        }
+
+        escape
+    }
+}
+
+/// Try to determine if `point` is in the set (Mandelbrot or Julia, depending on
+/// `config.fractal`, of `config.formula` or `config.custom_formula`), using at
+/// most `config.limit` iterations to decide.
 ///
-/// If `c` is not a member, return `Some(i)`, where `i` is the number of
-/// iterations it took for `c` to leave the circle of radius two centered on the
-/// origin. If `c` seems to be a member (more precisely, if we reached the
-/// iteration limit without being able to prove that `c` is not a member),
-/// return `None`.
-fn escape_time(c: Complex<f64>, limit: usize) -> Option<usize> {
-    let mut z = Complex { re: 0.0, im: 0.0 };
+/// If `point` is not a member, return `Outcome::Escaped(escape)`, where
+/// `escape.count` is the number of iterations it took for its orbit to leave the
+/// circle centered on the origin with a squared radius of `config.bailout_sqr`.
+/// If `point` seems to be a member (more precisely, if we reached the iteration
+/// limit without being able to prove that `point` is not a member), return
+/// `Outcome::Bounded`.
+pub(crate) fn escape_time<T: RenderFloat>(point: Complex<T>, config: &EscapeConfig) -> Outcome {
+    let (mut z, c) = config.fractal.initial_orbit(point);
+
+    // Custom formulas start from `z = c` in place of 0, as many of them (like
+    // `z^3 + c*sin(z)`) never leave 0
+    if config.custom_formula.is_some() && config.fractal == FractalKind::Mandelbrot {
+        z = c;
+    }
+
+    if config.bulb_check && in_main_bulbs(numeric::complex_to_f64(c)) {
+        return Outcome::default();
+    }
+
+    // Brent's cycle detection: compare against the orbit at the last power of
+    // two iterations. Only an exact repeat counts, after which the orbit
+    // cycles through values that have already failed to escape - so stopping
+    // early can never change the result.
+    let mut saved = z;
+    let mut period_limit = 1;
+    let mut period = 0;
+
+    // Always tracked in f64s, as derivatives grow far faster than orbits do
+    let mut tracker = OrbitTracker::new(config);
+
+    for i in 0..config.limit {
+        if (z.re * z.re + z.im * z.im).to_f64() > config.bailout_sqr {
+            return Outcome::Escaped(OrbitTracker::escape(tracker, i, numeric::complex_to_f64(z)));
+        }
+
+        if let Some(tracker) = &mut tracker {
+            tracker.step(numeric::complex_to_f64(z));
+        }
+
+        z = step(z, c, config);
+
+        if config.periodicity_check {
+            if z == saved {
+                // Go on around the cycle to where the orbit would have been at
+                // the iteration limit, as the interior is colored from there
+                if config.track_interior {
+                    let remaining = (config.limit - i - 1) % (period + 1);
+                    for _ in 0..remaining {
+                        z = step(z, c, config);
+                    }
+                }
+
+                return bounded(z, c, config);
+            }
+
+            period += 1;
+            if period == period_limit {
+                saved = z;
+                period_limit *= 2;
+                period = 0;
+            }
+        }
+    }
+
+    bounded(z, c, config)
+}
+
+/// Iterate `z` once with the constant `c`.
+fn step<T: RenderFloat>(z: Complex<T>, c: Complex<T>, config: &EscapeConfig) -> Complex<T> {
+    match &config.custom_formula {
+        // Custom formulas are only evaluated with f64s
+        Some(program) => numeric::complex_from_f64(
+            program.eval(numeric::complex_to_f64(z), numeric::complex_to_f64(c)),
+        ),
+        None => config.formula.step(z, c),
+    }
+}
+
+// Longest cycle `bounded` looks for
+const MAX_INTERIOR_PERIOD: usize = 64;
+// How close (squared) an orbit must come back to where it was to count as
+// having gone around its cycle, loose enough for the rounding of f32s
+const PERIOD_TOLERANCE_SQR: f64 = 1.0e-12;
+
+/// The `Outcome` of an orbit with the constant `c` that was at `z` when it was
+/// found not to escape.
+fn bounded<T: RenderFloat>(z: Complex<T>, c: Complex<T>, config: &EscapeConfig) -> Outcome {
+    if !config.track_interior {
+        return Outcome::default();
+    }
+
+    let period = config.find_period.then(|| {
+        let mut cycled = z;
+        (1..=MAX_INTERIOR_PERIOD).find(|_| {
+            cycled = step(cycled, c, config);
+            (numeric::complex_to_f64(cycled) - numeric::complex_to_f64(z)).norm_sqr()
+                < PERIOD_TOLERANCE_SQR
+        })
+    });
+
+    Outcome::Bounded(Bounded {
+        z: Some(numeric::complex_to_f64(z)),
+        period: period.flatten(),
+    })
+}
+
+// Margin kept inside the edges of the bulbs, so points rounded onto the wrong
+// side of an edge are still iterated
+const BULB_CHECK_MARGIN: f64 = 1.0e-12;
+
+/// Whether `c` is inside the main cardioid or the period 2 bulb of the
+/// Mandelbrot set, and so a member without needing to iterate it.
+fn in_main_bulbs(c: Complex<f64>) -> bool {
+    let y_sqr = c.im * c.im;
+
+    // Main cardioid: q(q + (x - 1/4)) < y^2 / 4, with q = (x - 1/4)^2 + y^2
+    let x = c.re - 0.25;
+    let q = x * x + y_sqr;
+    let in_cardioid = q * (q + x) < 0.25 * y_sqr - BULB_CHECK_MARGIN;
+
+    // Period 2 bulb: the disc of radius 1/4 around -1
+    let in_bulb = (c.re + 1.0) * (c.re + 1.0) + y_sqr < 0.0625 - BULB_CHECK_MARGIN;
+
+    in_cardioid || in_bulb
+}
 
-    for i in 0..limit {
-        if z.norm_sqr() > 4.0 {
-            return Some(i);
+/// `escape_time`, perturbed against the reference orbit when rendering a deep
+/// zoom - in which case `point` is the offset from the deep zoom's center.
+fn escape<T: RenderFloat>(point: Complex<T>, config: &EscapeConfig) -> Outcome {
+    match &config.reference {
+        Some(reference) => {
+            let mut tracker = OrbitTracker::new(config);
+            let escaped = reference.escape_time(
+                numeric::complex_to_f64(point),
+                config.limit,
+                config.bailout_sqr,
+                |z| {
+                    if let Some(tracker) = &mut tracker {
+                        tracker.step(z);
+                    }
+                },
+            );
+
+            match escaped {
+                Some((count, z)) => Outcome::Escaped(OrbitTracker::escape(tracker, count, z)),
+                None => Outcome::default(),
+            }
         }
+        None => escape_time(point, config),
+    }
+}
 
-        z = z * z + c;
+/// `escape` for each of `points`, into `escapes`.
+fn escape_each<T: RenderFloat>(
+    points: &[Complex<T>],
+    config: &EscapeConfig,
+    escapes: &mut [Outcome],
+) {
+    for (point, escape_result) in points.iter().zip(escapes) {
+        *escape_result = escape(*point, config);
     }
+}
 
-    None
+/// A `RenderFloat` that can be escaped a batch of points at a time, with the
+/// SIMD kernel where there is one.
+trait EscapeBatch: RenderFloat {
+    /// `escape` for each of `points`, into `escapes`.
+    fn escape_batch(points: &[Complex<Self>], config: &EscapeConfig, escapes: &mut [Outcome]) {
+        escape_each(points, config, escapes);
+    }
 }
 
+cfg_if! {
+    if #[cfg(feature = "simd")] {
+        impl EscapeBatch for f32 {
+            fn escape_batch(points: &[Complex<f32>], config: &EscapeConfig, escapes: &mut [Outcome]) {
+                if simd::supports(config) {
+                    simd::escape_time_lanes::<simd::F32x8>(points, config, escapes);
+                } else {
+                    escape_each(points, config, escapes);
+                }
+            }
+        }
+
+        impl EscapeBatch for f64 {
+            fn escape_batch(points: &[Complex<f64>], config: &EscapeConfig, escapes: &mut [Outcome]) {
+                if simd::supports(config) {
+                    simd::escape_time_lanes::<simd::F64x4>(points, config, escapes);
+                } else {
+                    escape_each(points, config, escapes);
+                }
+            }
+        }
+    } else {
+        impl EscapeBatch for f32 {}
+        impl EscapeBatch for f64 {}
+    }
+}
+
+impl EscapeBatch for DoubleDouble {}
+
 /// Given the row and column of a pixel in the output image, return the
 /// corresponding point on the complex plane.
 ///
 /// `bounds` is a pair giving the width and height of the image in pixels.
-/// `pixel` is a (column, row) pair indicating a particular pixel in that image.
-/// The `upper_left` and `lower_right` parameters are points on the complex
-/// plane designating the area our image covers.
-fn pixel_to_point(
+/// `pixel` is a (column, row) pair indicating a particular pixel in that image,
+/// with fractional parts addressing points within the pixel. The `upper_left`
+/// and `lower_right` parameters are points on the complex plane designating the
+/// area our image covers.
+fn pixel_to_point<T: RenderFloat>(
     bounds: (usize, usize),
-    pixel: (usize, usize),
-    upper_left: Complex<f64>,
-    lower_right: Complex<f64>,
-) -> Complex<f64> {
+    pixel: (f64, f64),
+    upper_left: Complex<T>,
+    lower_right: Complex<T>,
+) -> Complex<T> {
     let (width, height) = (
         lower_right.re - upper_left.re,
         upper_left.im - lower_right.im,
     );
 
     Complex {
-        re: upper_left.re + pixel.0 as f64 * width / bounds.0 as f64,
-        im: upper_left.im - pixel.1 as f64 * height / bounds.1 as f64,
+        re: upper_left.re + T::from_f64(pixel.0) * width / T::from_f64(bounds.0 as f64),
+        im: upper_left.im - T::from_f64(pixel.1) * height / T::from_f64(bounds.1 as f64),
         // Why subtraction here? pixel.1 increases as we go down,
         // but the imaginary component increases as we go up.
     }
 }
 
+/// The color of a point, given the result of `escape` for it.
+fn escape_color(outcome: Outcome, color_scheme: &ColorScheme) -> Rgba<u8> {
+    match outcome {
+        Outcome::Escaped(escape) => color_scheme.exterior_color(
+            escape.count,
+            escape.z,
+            escape.derivative,
+            escape.orbit.as_ref(),
+        ),
+        Outcome::Bounded(bounded) => color_scheme.interior_color(bounded.z, bounded.period),
+    }
+}
+
+/// The points of an `n` x `n` grid within each of `pixels`, starting at their
+/// upper left corners. The other arguments are the same as `render`.
+fn sample_points<T: RenderFloat>(
+    bounds: (usize, usize),
+    pixels: impl Iterator<Item = (usize, usize)>,
+    upper_left: Complex<T>,
+    lower_right: Complex<T>,
+    n: u8,
+) -> Vec<Complex<T>> {
+    let n = n.max(1) as usize;
+    let mut points = Vec::new();
+
+    for pixel in pixels {
+        for sub_row in 0..n {
+            for sub_column in 0..n {
+                let sub_pixel = (
+                    pixel.0 as f64 + sub_column as f64 / n as f64,
+                    pixel.1 as f64 + sub_row as f64 / n as f64,
+                );
+                points.push(pixel_to_point(bounds, sub_pixel, upper_left, lower_right));
+            }
+        }
+    }
+
+    points
+}
+
+/// The colors of `points`, escaping them in a single batch.
+fn point_colors<T: EscapeBatch>(
+    points: &[Complex<T>],
+    escape_config: &EscapeConfig,
+    color_scheme: &ColorScheme,
+) -> Vec<Rgba<u8>> {
+    let mut escapes = vec![Outcome::default(); points.len()];
+    T::escape_batch(points, escape_config, &mut escapes);
+
+    escapes
+        .into_iter()
+        .map(|escape| escape_color(escape, color_scheme))
+        .collect()
+}
+
+/// The average of each run of `n` x `n` `colors`, as sampled by
+/// `sample_points`.
+///
+/// Colors are weighted by their alpha, so transparent samples (whose color
+/// doesn't matter) only make the edges they're on softer, never darker.
+fn average_colors(colors: &[Rgba<u8>], n: u8) -> impl Iterator<Item = Rgba<u8>> + '_ {
+    let samples = (n.max(1) as usize).pow(2);
+
+    colors.chunks(samples).map(move |colors| {
+        let mut sums = [0usize; 3];
+        let mut alpha_sum = 0;
+
+        for color in colors {
+            let alpha = color.0[3] as usize;
+            for (sum, channel) in sums.iter_mut().zip(color.0) {
+                *sum += channel as usize * alpha;
+            }
+            alpha_sum += alpha;
+        }
+
+        if alpha_sum == 0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+
+        // Rounds the same as an unweighted average when every sample is opaque
+        let [r, g, b] = sums.map(|sum| ((sum + alpha_sum / 2) / alpha_sum) as u8);
+        let alpha = ((alpha_sum + samples / 2) / samples) as u8;
+
+        Rgba([r, g, b, alpha])
+    })
+}
+
 /// Render a rectangle of the Mandelbrot set into a buffer of pixels.
 ///
 /// The `bounds` argument gives the width and height of the buffer `pixels`,
-/// which holds one grayscale pixel per byte. The `upper_left` and `lower_right`
+/// which holds one RGBA pixel per element. The `upper_left` and `lower_right`
 /// arguments specify points on the complex plane corresponding to the upper-
 /// left and lower-right corners of the pixel buffer.
-fn render(
+///
+/// With `Antialiasing::Grid`, every pixel is supersampled. Otherwise each pixel
+/// is a single point, and `Antialiasing::Adaptive` is left to `refine_edges`.
+fn render<T: EscapeBatch>(
     pixels: &mut [Rgba<u8>],
     bounds: (usize, usize),
-    upper_left: Complex<f64>,
-    lower_right: Complex<f64>,
-    (r, g, b): (u8, u8, u8),
+    upper_left: Complex<T>,
+    lower_right: Complex<T>,
+    antialiasing: Antialiasing,
+    escape_config: &EscapeConfig,
+    color_scheme: &ColorScheme,
 ) {
+    let n = match antialiasing {
+        Antialiasing::Grid(n) => n,
+        Antialiasing::Off | Antialiasing::Adaptive(_) => 1,
+    };
+
     for row in 0..bounds.1 {
-        for column in 0..bounds.0 {
-            let point = pixel_to_point(bounds, (column, row), upper_left, lower_right);
+        let points = sample_points(
+            bounds,
+            (0..bounds.0).map(|column| (column, row)),
+            upper_left,
+            lower_right,
+            n,
+        );
+        let colors = point_colors(&points, escape_config, color_scheme);
+
+        for (pixel, color) in pixels[row * bounds.0..(row + 1) * bounds.0]
+            .iter_mut()
+            .zip(average_colors(&colors, n))
+        {
+            *pixel = color;
+        }
+    }
+}
+
+// Sum of per-channel differences above which a pixel is refined by adaptive
+// antialiasing
+pub(crate) const ADAPTIVE_AA_THRESHOLD: u32 = 48;
+
+pub(crate) fn color_distance(a: &Rgba<u8>, b: &Rgba<u8>) -> u32 {
+    a.0.iter()
+        .zip(b.0.iter())
+        .map(|(a, b)| a.abs_diff(*b) as u32)
+        .sum()
+}
+
+/// Supersample the pixels in row `row` of a rendered image that differ from
+/// any of their neighbors, writing them into `band`.
+///
+/// `image` is the full single-sampled image, of size `img_bounds`, and
+/// `band_upper_left` and `band_lower_right` are the corners of the row.
+#[allow(clippy::too_many_arguments)]
+fn refine_edges<T: EscapeBatch>(
+    band: &mut [Rgba<u8>],
+    row: usize,
+    image: &[Rgba<u8>],
+    img_bounds: (usize, usize),
+    band_upper_left: Complex<T>,
+    band_lower_right: Complex<T>,
+    n: u8,
+    escape_config: &EscapeConfig,
+    color_scheme: &ColorScheme,
+) {
+    let (width, height) = img_bounds;
+
+    let edges: Vec<usize> = (0..width)
+        .filter(|column| {
+            let color = &image[row * width + column];
+            let neighbors = [
+                (*column > 0).then(|| row * width + column - 1),
+                (column + 1 < width).then(|| row * width + column + 1),
+                (row > 0).then(|| (row - 1) * width + column),
+                (row + 1 < height).then(|| (row + 1) * width + column),
+            ];
+
+            neighbors
+                .iter()
+                .flatten()
+                .any(|i| color_distance(color, &image[*i]) > ADAPTIVE_AA_THRESHOLD)
+        })
+        .collect();
+
+    let points = sample_points(
+        (width, 1),
+        edges.iter().map(|column| (*column, 0)),
+        band_upper_left,
+        band_lower_right,
+        n,
+    );
+    let colors = point_colors(&points, escape_config, color_scheme);
+
+    for (column, color) in edges.into_iter().zip(average_colors(&colors, n)) {
+        band[column] = color;
+    }
+}
+
+/// The corners on the complex plane of row `top` of the image.
+fn band_corners<T: RenderFloat>(
+    img_bounds: (usize, usize),
+    top: usize,
+    upper_left: Complex<T>,
+    lower_right: Complex<T>,
+) -> (Complex<T>, Complex<T>) {
+    (
+        pixel_to_point(img_bounds, (0.0, top as f64), upper_left, lower_right),
+        pixel_to_point(
+            img_bounds,
+            (img_bounds.0 as f64, (top + 1) as f64),
+            upper_left,
+            lower_right,
+        ),
+    )
+}
+
+/// Call `f` with the index and pixels of each band of `rows` rows of
+/// `pixels`, in parallel with the `parallel` feature.
+fn for_each_band<F>(pixels: &mut [Rgba<u8>], width: usize, rows: usize, f: F)
+where
+    F: Fn(usize, &mut [Rgba<u8>]) + Send + Sync,
+{
+    let band_len = width * rows;
+
+    cfg_if! {
+        if #[cfg(feature = "parallel")] {
+            use rayon::prelude::*;
+            let bands: Vec<(usize, &mut [Rgba<u8>])> = pixels
+                .chunks_mut(band_len)
+                .enumerate()
+                .collect::<Vec<(usize, &mut [Rgba<u8>])>>();
+            let bands_iter = bands.into_par_iter();
+        } else {
+            let bands = pixels.chunks_mut(band_len).enumerate();
+            let bands_iter = bands.into_iter();
+        }
+    }
 
-            pixels[row * bounds.0 + column] = match escape_time(point, 255) {
-                None => Rgba([10, 10, 25, 255]),
-                Some(count) => Rgba([r % count as u8, g % count as u8, b % count as u8, 255]),
-            };
+    bands_iter.for_each(|(i, band)| f(i, band));
+}
+
+// Rows in each band of the image subdivided on its own
+const SUBDIVISION_BAND_ROWS: usize = 32;
+// Rectangles this narrow or short are iterated pixel by pixel
+const MIN_SUBDIVISION_SIZE: usize = 4;
+
+/// A band of an image being rendered by `RenderStrategy::Subdivide`, holding
+/// the escape result of each pixel once it's known.
+struct SubdividedBand<'a, T: RenderFloat> {
+    escapes: Vec<Option<Outcome>>,
+    width: usize,
+    /// Corners of each row, as `render_image` would map them.
+    row_corners: Vec<(Complex<T>, Complex<T>)>,
+    escape_config: &'a EscapeConfig,
+}
+
+impl<'a, T: RenderFloat> SubdividedBand<'a, T> {
+    fn escape_at(&mut self, column: usize, row: usize) -> Outcome {
+        let i = row * self.width + column;
+
+        if let Some(escape) = self.escapes[i] {
+            return escape;
+        }
+
+        // Map the pixel to a point exactly as `render` does for its row, so
+        // every pixel is iterated from the same point as the per-pixel strategy
+        let (row_upper_left, row_lower_right) = self.row_corners[row];
+        let point = pixel_to_point(
+            (self.width, 1),
+            (column as f64, 0.0),
+            row_upper_left,
+            row_lower_right,
+        );
+        let escape = escape(point, self.escape_config);
+        self.escapes[i] = Some(escape);
+
+        escape
+    }
+
+    /// Fill in the rectangle from `(left, top)` up to but not including
+    /// `(right, bottom)`.
+    ///
+    /// If everything around the border of the rectangle escaped with the same
+    /// count, so did everything inside it, as the regions of each escape
+    /// count are connected. Otherwise, the rectangle is split in two (sharing
+    /// the dividing line) and each half is subdivided.
+    ///
+    /// Rectangles bordered by points that didn't escape are never filled in,
+    /// since thinner-than-a-pixel strands of escaping points can run through
+    /// them without crossing any sampled point of the border.
+    fn subdivide(&mut self, left: usize, top: usize, right: usize, bottom: usize) {
+        let count = |outcome: Outcome| outcome.escaped().map(|escape| escape.count);
+
+        if right - left <= MIN_SUBDIVISION_SIZE || bottom - top <= MIN_SUBDIVISION_SIZE {
+            for row in top..bottom {
+                for column in left..right {
+                    self.escape_at(column, row);
+                }
+            }
+
+            return;
+        }
+
+        let corner = self.escape_at(left, top);
+        let mut uniform = corner.escaped().is_some();
+
+        for column in left..right {
+            uniform &= count(self.escape_at(column, top)) == count(corner);
+            uniform &= count(self.escape_at(column, bottom - 1)) == count(corner);
+        }
+
+        for row in top..bottom {
+            uniform &= count(self.escape_at(left, row)) == count(corner);
+            uniform &= count(self.escape_at(right - 1, row)) == count(corner);
+        }
+
+        if uniform {
+            for row in top + 1..bottom - 1 {
+                for column in left + 1..right - 1 {
+                    self.escapes[row * self.width + column].get_or_insert(corner);
+                }
+            }
+        } else if right - left >= bottom - top {
+            let middle = left + (right - left) / 2;
+            self.subdivide(left, top, middle + 1, bottom);
+            self.subdivide(middle, top, right, bottom);
+        } else {
+            let middle = top + (bottom - top) / 2;
+            self.subdivide(left, top, right, middle + 1);
+            self.subdivide(left, middle, right, bottom);
         }
     }
 }
 
+// Only every this many pixels across and down is sampled for the histogram of
+// an image
+const HISTOGRAM_STRIDE: usize = 2;
+
+/// The histogram of escape counts over the image, sampled with the same points
+/// as `render_image` iterates.
+fn iteration_histogram<T: EscapeBatch>(
+    img_bounds: (usize, usize),
+    upper_left: Complex<T>,
+    lower_right: Complex<T>,
+    escape_config: &EscapeConfig,
+) -> IterationHistogram {
+    let row_counts = |row: usize| -> Vec<usize> {
+        let (row_upper_left, row_lower_right) =
+            band_corners(img_bounds, row, upper_left, lower_right);
+        let points = sample_points(
+            (img_bounds.0, 1),
+            (0..img_bounds.0)
+                .step_by(HISTOGRAM_STRIDE)
+                .map(|column| (column, 0)),
+            row_upper_left,
+            row_lower_right,
+            1,
+        );
+
+        let mut escapes = vec![Outcome::default(); points.len()];
+        T::escape_batch(&points, escape_config, &mut escapes);
+        escapes
+            .into_iter()
+            .filter_map(|outcome| outcome.escaped().map(|escape| escape.count))
+            .collect()
+    };
+
+    let rows = (0..img_bounds.1).step_by(HISTOGRAM_STRIDE);
+
+    cfg_if! {
+        if #[cfg(feature = "parallel")] {
+            use rayon::prelude::*;
+            let counts: Vec<Vec<usize>> = rows.collect::<Vec<usize>>().into_par_iter().map(row_counts).collect();
+        } else {
+            let counts: Vec<Vec<usize>> = rows.map(row_counts).collect();
+        }
+    }
+
+    IterationHistogram::new(escape_config.limit, counts.into_iter().flatten())
+}
+
+/// Render the whole image into `pixels`, iterating with `T`.
+///
+/// `corners` are the `upper_left` and `lower_right` of the image on the complex
+/// plane, which are converted to `T` before mapping any pixels to points.
+///
+/// With `subdivide`, single samples are filled in by subdividing bands of the
+/// image rather than iterating every pixel, which only gives the same image
+/// when colors depend on nothing but the escape count.
+///
+/// If `color_scheme` needs a histogram, it's gathered in a first pass.
+fn render_image<T: EscapeBatch>(
+    pixels: &mut [Rgba<u8>],
+    img_bounds: (usize, usize),
+    corners: (Complex<f64>, Complex<f64>),
+    antialiasing: Antialiasing,
+    subdivide: bool,
+    escape_config: &EscapeConfig,
+    mut color_scheme: ColorScheme,
+) {
+    let upper_left = numeric::complex_from_f64::<T>(corners.0);
+    let lower_right = numeric::complex_from_f64::<T>(corners.1);
+
+    if color_scheme.needs_histogram() {
+        color_scheme.set_histogram(iteration_histogram(
+            img_bounds,
+            upper_left,
+            lower_right,
+            escape_config,
+        ));
+    }
+    let color_scheme = &color_scheme;
+
+    // Slice up `pixels` into horizontal bands for parallel processing
+    if subdivide {
+        for_each_band(
+            pixels,
+            img_bounds.0,
+            SUBDIVISION_BAND_ROWS,
+            |index, band| {
+                let top = index * SUBDIVISION_BAND_ROWS;
+                let rows = band.len() / img_bounds.0;
+
+                let mut subdivided = SubdividedBand {
+                    escapes: vec![None; band.len()],
+                    width: img_bounds.0,
+                    row_corners: (top..top + rows)
+                        .map(|row| band_corners(img_bounds, row, upper_left, lower_right))
+                        .collect(),
+                    escape_config,
+                };
+                subdivided.subdivide(0, 0, img_bounds.0, rows);
+
+                for (pixel, escape) in band.iter_mut().zip(subdivided.escapes) {
+                    *pixel = escape_color(escape.unwrap_or_default(), color_scheme);
+                }
+            },
+        );
+    } else {
+        for_each_band(pixels, img_bounds.0, 1, |top, band| {
+            let (band_upper_left, band_lower_right) =
+                band_corners(img_bounds, top, upper_left, lower_right);
+
+            render(
+                band,
+                (img_bounds.0, 1),
+                band_upper_left,
+                band_lower_right,
+                antialiasing,
+                escape_config,
+                color_scheme,
+            );
+        });
+    }
+
+    // Second pass for adaptive antialiasing, now that every pixel's neighbors
+    // are known
+    if let Antialiasing::Adaptive(n) = antialiasing {
+        let single_sampled = pixels.to_vec();
+
+        for_each_band(pixels, img_bounds.0, 1, |top, band| {
+            let (band_upper_left, band_lower_right) =
+                band_corners(img_bounds, top, upper_left, lower_right);
+
+            refine_edges(
+                band,
+                top,
+                &single_sampled,
+                img_bounds,
+                band_upper_left,
+                band_lower_right,
+                n,
+                escape_config,
+                color_scheme,
+            );
+        });
+    }
+}
+
+// Every transform leaves the alpha channel as it is
 pub fn apply_image_transforms_in_place(
     img_params: &ImageParams,
     image: &mut ImageBuffer<Rgba<u8>, Vec<u8>>,
@@ -102,51 +989,165 @@ pub fn apply_image_transforms_in_place(
     }
 }
 
+/// Settings for rendering that don't change the resulting image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderOptions {
+    /// Limits the output dimensions are clamped to.
+    pub limits: OutputLimits,
+    /// Skip iterating points known to be in the set, from the main cardioid
+    /// and period 2 bulb tests and periodicity checking. Produces an identical
+    /// image, faster for images with lots of the set's interior in them.
+    pub interior_checks: bool,
+    pub strategy: RenderStrategy,
+    /// Most `ImageParams::render_work` to render, beyond which rendering fails.
+    pub max_work: u64,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            limits: OutputLimits::default(),
+            interior_checks: true,
+            strategy: RenderStrategy::default(),
+            max_work: MAX_RENDER_WORK,
+        }
+    }
+}
+
+/// How to work out which pixels to iterate.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RenderStrategy {
+    /// Iterate every pixel.
+    #[default]
+    PerPixel,
+    /// Recursively subdivide the image into rectangles, filling in any where
+    /// everything around their border escaped with the same count without
+    /// iterating them (Mariani-Silver). Faster for images with large regions
+    /// outside the set.
+    ///
+    /// Only used when it gives the same image as `PerPixel`: for coloring that
+    /// depends only on escape counts, of formulas whose escape count regions
+    /// are connected, and without `Antialiasing::Grid`. Otherwise, this falls
+    /// back to `PerPixel`.
+    Subdivide,
+}
+
 // Generate a PNG deterministically from the given set of `ImageParams`
 pub fn create_png(img_params: &ImageParams) -> Result<Vec<u8>, errors::ImageProcessingError> {
-    let img_bounds = img_params.get_bounds();
+    create_png_with_options(img_params, &RenderOptions::default())
+}
+
+// Same as `create_png`, with the output dimensions clamped to `limits`
+pub fn create_png_within(
+    img_params: &ImageParams,
+    limits: &OutputLimits,
+) -> Result<Vec<u8>, errors::ImageProcessingError> {
+    create_png_with_options(
+        img_params,
+        &RenderOptions {
+            limits: *limits,
+            ..RenderOptions::default()
+        },
+    )
+}
+
+// Same as `create_png`, with the given `RenderOptions`
+pub fn create_png_with_options(
+    img_params: &ImageParams,
+    options: &RenderOptions,
+) -> Result<Vec<u8>, errors::ImageProcessingError> {
+    let image_buffer = render_with_options(img_params, options)?;
+
+    // Result image buffer
+    let mut buffer = vec![];
+    let encoder = PngEncoder::new(&mut buffer);
+
+    // Write image_buffer to result buffer
+    encoder
+        .write_image(
+            &image_buffer,
+            image_buffer.width(),
+            image_buffer.height(),
+            ColorType::Rgba8,
+        )
+        .map_err(|e| errors::ImageProcessingError::Default {
+            message: format!("Failed to encode output image: {}", e),
+        })?;
+
+    Ok(buffer.to_vec())
+}
+
+// Same as `create_png_with_options`, without encoding the image
+pub fn render_with_options(
+    img_params: &ImageParams,
+    options: &RenderOptions,
+) -> Result<RgbaImage, errors::ImageProcessingError> {
+    img_params
+        .validate_work(&options.limits, options.max_work)
+        .map_err(|e| errors::ImageProcessingError::Default {
+            message: e.to_string(),
+        })?;
+
+    let img_bounds = img_params.get_bounds_within(&options.limits);
     let mut pixels = vec![Rgba([0, 0, 0, 255]); img_bounds.0 * img_bounds.1];
+    let escape_config = EscapeConfig::from_params(img_params, options.interior_checks)?;
 
-    // Scope of slicing up `pixels` into horizontal bands for parallel processing
-    {
-        cfg_if! {
-            if #[cfg(feature = "parallel")] {
-                use rayon::prelude::*;
-                let bands: Vec<(usize, &mut [Rgba<u8>])> = pixels
-                    .chunks_mut(img_bounds.0)
-                    .enumerate()
-                    .collect::<Vec<(usize, &mut [Rgba<u8>])>>();
-                let bands_iter = bands.into_par_iter();
-            } else {
-                let bands = pixels.chunks_mut(img_bounds.0).enumerate();
-                let bands_iter = bands.into_iter();
-            }
-        }
+    // Deep zooms map pixels to offsets from the center, rather than to points
+    let (upper_left, lower_right) = match &img_params.deep_zoom {
+        Some(deep_zoom) => deep_zoom.offset_corners(),
+        None => (img_params.upper_left, img_params.lower_right),
+    };
 
-        bands_iter.for_each(|(i, band)| {
-            let top = i;
-            let band_bounds = (img_bounds.0, 1);
-            let band_upper_left = pixel_to_point(
-                img_bounds,
-                (0, top),
-                img_params.upper_left,
-                img_params.lower_right,
-            );
-            let band_lower_right = pixel_to_point(
-                img_bounds,
-                (img_bounds.0, top + 1),
-                img_params.upper_left,
-                img_params.lower_right,
-            );
+    let pixel_size = ((lower_right.re - upper_left.re) / img_bounds.0 as f64)
+        .abs()
+        .max(((upper_left.im - lower_right.im) / img_bounds.1 as f64).abs());
+    let color_scheme = ColorScheme::from_params(img_params, pixel_size);
 
-            render(
-                band,
-                band_bounds,
-                band_upper_left,
-                band_lower_right,
-                img_params.rgb_consts,
-            );
-        });
+    let precision = match (&img_params.deep_zoom, &img_params.custom_formula) {
+        // Pixel offsets from a deep zoom's center are always f64s, as are
+        // custom formulas
+        (Some(_), _) | (_, Some(_)) => Precision::Double,
+        (None, None) => Precision::for_pixel_size(pixel_size),
+    };
+
+    let subdivide = options.strategy == RenderStrategy::Subdivide
+        && color_scheme.depends_only_on_count()
+        && escape_config.has_connected_level_sets()
+        && !matches!(img_params.antialiasing, Antialiasing::Grid(_));
+
+    debug!(
+        "rendering with precision {:?}, subdividing: {}",
+        precision, subdivide
+    );
+
+    match precision {
+        Precision::Single => render_image::<f32>(
+            &mut pixels,
+            img_bounds,
+            (upper_left, lower_right),
+            img_params.antialiasing,
+            subdivide,
+            &escape_config,
+            color_scheme,
+        ),
+        Precision::Double => render_image::<f64>(
+            &mut pixels,
+            img_bounds,
+            (upper_left, lower_right),
+            img_params.antialiasing,
+            subdivide,
+            &escape_config,
+            color_scheme,
+        ),
+        Precision::DoubleDouble => render_image::<DoubleDouble>(
+            &mut pixels,
+            img_bounds,
+            (upper_left, lower_right),
+            img_params.antialiasing,
+            subdivide,
+            &escape_config,
+            color_scheme,
+        ),
     }
 
     // Flatten 2d pixel array to 1d
@@ -163,21 +1164,145 @@ pub fn create_png(img_params: &ImageParams) -> Result<Vec<u8>, errors::ImageProc
 
     apply_image_transforms_in_place(img_params, &mut image_buffer);
 
-    // Result image buffer
-    let mut buffer = vec![];
-    let encoder = PngEncoder::new(&mut buffer);
+    Ok(image_buffer)
+}
 
-    // Write image_buffer to result buffer
-    encoder
-        .write_image(
-            &image_buffer,
-            OUTPUT_WIDTH as u32,
-            OUTPUT_HEIGHT as u32,
-            ColorType::Rgba8,
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::coloring::ColoringMode;
+    use crate::image_params::IterationLimit;
+    use enumflags2::BitFlags;
+
+    pub(crate) fn view(
+        upper_left: (f64, f64),
+        lower_right: (f64, f64),
+        bounds: (usize, usize),
+        limit: u32,
+    ) -> ImageParams {
+        ImageParams {
+            bounds,
+            upper_left: Complex::new(upper_left.0, upper_left.1),
+            lower_right: Complex::new(lower_right.0, lower_right.1),
+            zoom_factor: 1.0,
+            rgb_consts: (83, 151, 229),
+            transform_flags: BitFlags::EMPTY,
+            coloring: ColoringMode::EscapeTime,
+            palette: None,
+            iteration_limit: IterationLimit::Fixed(limit),
+            bailout_radius: None,
+            antialiasing: Antialiasing::Off,
+            deep_zoom: None,
+            fractal: FractalKind::Mandelbrot,
+            formula: Formula::Quadratic,
+            custom_formula: None,
+            interior: InteriorColoring::Fixed,
+            transparent_band: None,
+        }
+    }
+
+    pub(crate) fn whole_set(bounds: (usize, usize)) -> ImageParams {
+        view((-2.2, 1.5), (0.8, -1.5), bounds, 500)
+    }
+
+    pub(crate) fn seahorse_valley(bounds: (usize, usize)) -> ImageParams {
+        view((-0.7456, 0.1338), (-0.7416, 0.1298), bounds, 1000)
+    }
+
+    // The antenna of the set, a filament thinner than a pixel
+    fn antenna(bounds: (usize, usize)) -> ImageParams {
+        view((-1.9, 0.004), (-1.7, -0.004), bounds, 500)
+    }
+
+    // A minibrot on the antenna, small enough to render with f64s
+    fn minibrot(bounds: (usize, usize)) -> ImageParams {
+        let (re, half_extent) = (-1.754_877_666_246_7, 2.0e-6);
+        view(
+            (re - half_extent, half_extent),
+            (re + half_extent, -half_extent),
+            bounds,
+            2000,
         )
-        .map_err(|e| errors::ImageProcessingError::Default {
-            message: format!("Failed to encode output image: {}", e),
-        })?;
+    }
 
-    Ok(buffer.to_vec())
+    fn render(img_params: &ImageParams, options: &RenderOptions) -> RgbaImage {
+        render_with_options(img_params, options).unwrap()
+    }
+
+    #[test]
+    fn interior_checks_leave_image_unchanged() {
+        let bounds = (40, 30);
+        let mut views = vec![
+            whole_set(bounds),
+            seahorse_valley(bounds),
+            minibrot(bounds),
+            // Around the period 2 bulb
+            view((-1.3, 0.3), (-0.7, -0.3), bounds, 500),
+        ];
+
+        let mut julia = view((-1.6, 1.2), (1.6, -1.2), bounds, 500);
+        for c in [Complex::new(-0.12, 0.75), Complex::new(-1.0, 0.0)] {
+            julia.fractal = FractalKind::Julia { c };
+            views.push(julia.clone());
+        }
+
+        let with_checks = RenderOptions::default();
+        let without_checks = RenderOptions {
+            interior_checks: false,
+            ..with_checks
+        };
+
+        for base in views {
+            for (coloring, interior) in [
+                (ColoringMode::EscapeTime, InteriorColoring::Fixed),
+                (ColoringMode::Smooth, InteriorColoring::Period),
+                (ColoringMode::Smooth, InteriorColoring::Magnitude),
+            ] {
+                let img_params = ImageParams {
+                    coloring,
+                    interior,
+                    ..base.clone()
+                };
+
+                assert!(
+                    render(&img_params, &with_checks) == render(&img_params, &without_checks),
+                    "interior checks changed {:?}",
+                    img_params
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn subdivide_matches_per_pixel() {
+        let per_pixel = RenderOptions::default();
+        let subdivide = RenderOptions {
+            strategy: RenderStrategy::Subdivide,
+            ..per_pixel
+        };
+
+        // Sizes that are and aren't multiples of the bands and smallest
+        // rectangles subdivided
+        for bounds in [(16, 16), (64, 64), (37, 53), (100, 33), (70, 97)] {
+            let mut multibrot = whole_set(bounds);
+            multibrot.formula = Formula::Multibrot(3);
+            let mut adaptive = seahorse_valley(bounds);
+            adaptive.antialiasing = Antialiasing::Adaptive(2);
+
+            for img_params in [
+                whole_set(bounds),
+                seahorse_valley(bounds),
+                antenna(bounds),
+                minibrot(bounds),
+                multibrot,
+                adaptive,
+            ] {
+                assert!(
+                    render(&img_params, &per_pixel) == render(&img_params, &subdivide),
+                    "subdividing changed {:?}",
+                    img_params
+                );
+            }
+        }
+    }
 }
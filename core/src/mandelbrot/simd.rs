@@ -0,0 +1,354 @@
+//! `escape_time` for several points at once, with portable SIMD vectors.
+//!
+//! Every lane goes through exactly the same operations, in the same order, as
+//! `escape_time` does for a single point, so the results are bit-identical.
+
+use num::Complex;
+use std::ops::{Add, BitAnd, BitXor, Mul, Neg, Sub};
+use wide::{CmpEq, CmpGt};
+
+use super::{in_main_bulbs, Escape, EscapeConfig, Outcome};
+use crate::numeric::{self, Arithmetic, RenderFloat};
+
+/// A SIMD vector of `RenderFloat`s, one point of an orbit per lane.
+///
+/// Comparisons give masks with every bit of a lane set where they hold.
+pub(super) trait Lanes: Arithmetic + BitAnd<Output = Self> + BitXor<Output = Self> {
+    type Scalar: RenderFloat;
+    const LANES: usize;
+
+    fn from_slice(values: &[Self::Scalar]) -> Self;
+    fn splat(value: Self::Scalar) -> Self;
+    fn lane(self, i: usize) -> Self::Scalar;
+    fn cmp_gt(self, other: Self) -> Self;
+    fn cmp_eq(self, other: Self) -> Self;
+    /// Bit `i` is set if lane `i` of the mask is.
+    fn mask_bits(self) -> u32;
+    /// Lanes of `t` where the mask is set, and of `f` elsewhere.
+    fn blend(self, t: Self, f: Self) -> Self;
+
+    /// The scalar that lanes of an orbit's squared magnitude are above exactly
+    /// when `escape_time` sees them as above `bailout_sqr`, which it compares
+    /// as f64s.
+    fn bailout_threshold(bailout_sqr: f64) -> Self::Scalar;
+}
+
+macro_rules! impl_lanes {
+    ($name:ident, $vector:ty, $scalar:ty, $lanes:expr) => {
+        #[derive(Clone, Copy)]
+        pub(super) struct $name($vector);
+
+        impl Add for $name {
+            type Output = Self;
+
+            fn add(self, other: Self) -> Self {
+                Self(self.0 + other.0)
+            }
+        }
+
+        impl Sub for $name {
+            type Output = Self;
+
+            fn sub(self, other: Self) -> Self {
+                Self(self.0 - other.0)
+            }
+        }
+
+        impl Mul for $name {
+            type Output = Self;
+
+            fn mul(self, other: Self) -> Self {
+                Self(self.0 * other.0)
+            }
+        }
+
+        // `wide` negates by subtracting from 0, which loses the sign of -0
+        impl Neg for $name {
+            type Output = Self;
+
+            fn neg(self) -> Self {
+                Self(self.0 ^ <$vector>::splat(-0.0))
+            }
+        }
+
+        impl BitAnd for $name {
+            type Output = Self;
+
+            fn bitand(self, other: Self) -> Self {
+                Self(self.0 & other.0)
+            }
+        }
+
+        impl BitXor for $name {
+            type Output = Self;
+
+            fn bitxor(self, other: Self) -> Self {
+                Self(self.0 ^ other.0)
+            }
+        }
+
+        impl Arithmetic for $name {
+            fn abs(self) -> Self {
+                Self(self.0.abs())
+            }
+        }
+
+        impl Lanes for $name {
+            type Scalar = $scalar;
+            const LANES: usize = $lanes;
+
+            fn from_slice(values: &[$scalar]) -> Self {
+                let mut array = [0.0; $lanes];
+                array.copy_from_slice(values);
+                Self(<$vector>::from(array))
+            }
+
+            fn splat(value: $scalar) -> Self {
+                Self(<$vector>::splat(value))
+            }
+
+            fn lane(self, i: usize) -> $scalar {
+                self.0.to_array()[i]
+            }
+
+            fn cmp_gt(self, other: Self) -> Self {
+                Self(self.0.cmp_gt(other.0))
+            }
+
+            fn cmp_eq(self, other: Self) -> Self {
+                Self(self.0.cmp_eq(other.0))
+            }
+
+            fn mask_bits(self) -> u32 {
+                self.0.move_mask() as u32
+            }
+
+            fn blend(self, t: Self, f: Self) -> Self {
+                Self(self.0.blend(t.0, f.0))
+            }
+
+            fn bailout_threshold(bailout_sqr: f64) -> $scalar {
+                // The largest scalar at most `bailout_sqr`, as anything above
+                // it is also above `bailout_sqr`
+                let threshold = bailout_sqr as $scalar;
+                if threshold as f64 > bailout_sqr {
+                    <$scalar>::from_bits(threshold.to_bits() - 1)
+                } else {
+                    threshold
+                }
+            }
+        }
+    };
+}
+
+impl_lanes!(F32x8, wide::f32x8, f32, 8);
+impl_lanes!(F64x4, wide::f64x4, f64, 4);
+
+/// Whether `escape_time_lanes` can iterate points with `config`: only the
+/// built-in formulas are vectorized, without derivatives, orbit stats or
+/// interior orbits, and deep zooms are perturbed instead.
+pub(super) fn supports(config: &EscapeConfig) -> bool {
+    config.reference.is_none()
+        && config.custom_formula.is_none()
+        && !config.track_derivative
+        && config.orbit_coloring.is_none()
+        && !config.track_interior
+}
+
+// Most lanes of any `Lanes`
+const MAX_LANES: usize = 8;
+
+/// `escape_time` for each of `points`, into `escapes`, `L::LANES` points at a
+/// time. `config` must be `supports`ed.
+pub(super) fn escape_time_lanes<L: Lanes>(
+    points: &[Complex<L::Scalar>],
+    config: &EscapeConfig,
+    escapes: &mut [Outcome],
+) {
+    let zero = L::Scalar::from_f64(0.0);
+    let origin = Complex { re: zero, im: zero };
+
+    for (points, escapes) in points.chunks(L::LANES).zip(escapes.chunks_mut(L::LANES)) {
+        // Pad out the last group with copies of its last point, which finish
+        // along with it
+        let mut z0 = [origin; MAX_LANES];
+        let mut c = [origin; MAX_LANES];
+        for lane in 0..L::LANES {
+            (z0[lane], c[lane]) = config
+                .fractal
+                .initial_orbit(points[lane.min(points.len() - 1)]);
+        }
+
+        escape_time_group::<L>(&z0[..L::LANES], &c[..L::LANES], config, escapes);
+    }
+}
+
+/// `escape_time` for a single group of `L::LANES` orbits starting at `z0`,
+/// iterated with the constants `c`, into the first `escapes`.
+fn escape_time_group<L: Lanes>(
+    z0: &[Complex<L::Scalar>],
+    c: &[Complex<L::Scalar>],
+    config: &EscapeConfig,
+    escapes: &mut [Outcome],
+) {
+    let zero = L::Scalar::from_f64(0.0);
+    let one = L::Scalar::from_f64(1.0);
+
+    let lanes = |f: &dyn Fn(usize) -> L::Scalar| {
+        let mut values = [zero; MAX_LANES];
+        for (lane, value) in values.iter_mut().take(L::LANES).enumerate() {
+            *value = f(lane);
+        }
+        L::from_slice(&values[..L::LANES])
+    };
+
+    let mut z = Complex {
+        re: lanes(&|lane| z0[lane].re),
+        im: lanes(&|lane| z0[lane].im),
+    };
+    let c_lanes = Complex {
+        re: lanes(&|lane| c[lane].re),
+        im: lanes(&|lane| c[lane].im),
+    };
+    let threshold = L::splat(L::bailout_threshold(config.bailout_sqr));
+
+    // Lanes still being iterated
+    let mut active = lanes(&|lane| {
+        let in_bulbs = config.bulb_check && in_main_bulbs(numeric::complex_to_f64(c[lane]));
+        if in_bulbs {
+            zero
+        } else {
+            one
+        }
+    })
+    .cmp_eq(L::splat(one));
+
+    for escape in escapes.iter_mut() {
+        *escape = Outcome::default();
+    }
+
+    // The same cycle detection as `escape_time`, whose schedule is the same for
+    // every lane
+    let mut saved = z;
+    let mut period_limit = 1;
+    let mut period = 0;
+
+    for i in 0..config.limit {
+        if active.mask_bits() == 0 {
+            break;
+        }
+
+        let escaped = (z.re * z.re + z.im * z.im).cmp_gt(threshold) & active;
+        let escaped_bits = escaped.mask_bits();
+        if escaped_bits != 0 {
+            for (lane, escape) in escapes.iter_mut().enumerate() {
+                if escaped_bits & (1 << lane) != 0 {
+                    *escape = Outcome::Escaped(Escape {
+                        count: i,
+                        z: numeric::complex_to_f64(Complex {
+                            re: z.re.lane(lane),
+                            im: z.im.lane(lane),
+                        }),
+                        derivative: None,
+                        orbit: None,
+                    });
+                }
+            }
+            active = active ^ escaped;
+        }
+
+        // Lanes that are finished keep their last value, so they can't
+        // overflow into infinities or NaNs that slow down the rest
+        let next = config.formula.step(z, c_lanes);
+        z = Complex {
+            re: active.blend(next.re, z.re),
+            im: active.blend(next.im, z.im),
+        };
+
+        if config.periodicity_check {
+            let repeated = z.re.cmp_eq(saved.re) & z.im.cmp_eq(saved.im) & active;
+            active = active ^ repeated;
+
+            period += 1;
+            if period == period_limit {
+                saved = z;
+                period_limit *= 2;
+                period = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{seahorse_valley, whole_set};
+    use super::super::{escape_time, pixel_to_point};
+    use super::*;
+    use crate::image_params::ImageParams;
+
+    /// The point of each pixel of `img_params`, row by row.
+    fn pixel_points<T: RenderFloat>(img_params: &ImageParams) -> Vec<Complex<T>> {
+        let (width, height) = img_params.bounds;
+        let upper_left = numeric::complex_from_f64(img_params.upper_left);
+        let lower_right = numeric::complex_from_f64(img_params.lower_right);
+
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x as f64, y as f64)))
+            .map(|pixel| pixel_to_point(img_params.bounds, pixel, upper_left, lower_right))
+            .collect()
+    }
+
+    fn assert_lanes_match_scalar<L: Lanes>(points: &[Complex<L::Scalar>], config: &EscapeConfig) {
+        let mut escapes = vec![Outcome::default(); points.len()];
+        escape_time_lanes::<L>(points, config, &mut escapes);
+
+        for (point, escape) in points.iter().zip(escapes) {
+            let at = numeric::complex_to_f64(*point);
+            assert_eq!(escape, escape_time(*point, config), "at {:?}", at);
+        }
+    }
+
+    #[test]
+    fn lanes_match_escape_time() {
+        // 391 and 117 points, neither a multiple of any number of lanes
+        for img_params in [whole_set((23, 17)), seahorse_valley((13, 9))] {
+            let points_f32 = pixel_points::<f32>(&img_params);
+            let points_f64 = pixel_points::<f64>(&img_params);
+
+            for interior_checks in [true, false] {
+                let config = EscapeConfig::from_params(&img_params, interior_checks).unwrap();
+                assert!(supports(&config));
+
+                // Padding any part of the last group
+                for count in [1, 3, 4, 5, 7, 8, 9, points_f32.len()] {
+                    assert_lanes_match_scalar::<F32x8>(&points_f32[..count], &config);
+                    assert_lanes_match_scalar::<F64x4>(&points_f64[..count], &config);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lanes_match_escape_time_when_bailout_rounds_up() {
+        // A squared bailout radius just below an f32, which it rounds up to
+        let re = 2.000_000_2_f32;
+        let above = re * re;
+        let below = f32::from_bits(above.to_bits() - 1);
+        let bailout_sqr = above as f64 - (above - below) as f64 / 4.0;
+        assert!(bailout_sqr as f32 == above);
+
+        let mut config = EscapeConfig::from_params(&whole_set((8, 8)), true).unwrap();
+        config.bailout_sqr = bailout_sqr;
+
+        // An orbit that goes through exactly `above` after one iteration, and
+        // points either side of it
+        let before = f32::from_bits(re.to_bits() - 1);
+        let after = f32::from_bits(re.to_bits() + 1);
+        let points: Vec<Complex<f32>> = [re, before, after, 1.5, 0.25]
+            .into_iter()
+            .map(|re| Complex { re, im: 0.0 })
+            .collect();
+
+        assert_lanes_match_scalar::<F32x8>(&points, &config);
+    }
+}
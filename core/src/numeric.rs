@@ -0,0 +1,347 @@
+use num::Complex;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// The arithmetic formulas are iterated with, shared by `RenderFloat`s and
+/// SIMD vectors of them.
+pub trait Arithmetic:
+    Copy + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Neg<Output = Self>
+{
+    fn abs(self) -> Self;
+}
+
+/// A floating point type the escape-time loop can be run with.
+pub trait RenderFloat: Arithmetic + PartialEq + Send + Sync + Div<Output = Self> {
+    fn from_f64(value: f64) -> Self;
+    fn to_f64(self) -> f64;
+}
+
+impl Arithmetic for f32 {
+    fn abs(self) -> Self {
+        f32::abs(self)
+    }
+}
+
+impl RenderFloat for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Arithmetic for f64 {
+    fn abs(self) -> Self {
+        f64::abs(self)
+    }
+}
+
+impl RenderFloat for f64 {
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+pub fn complex_from_f64<T: RenderFloat>(z: Complex<f64>) -> Complex<T> {
+    Complex {
+        re: T::from_f64(z.re),
+        im: T::from_f64(z.im),
+    }
+}
+
+pub fn complex_to_f64<T: RenderFloat>(z: Complex<T>) -> Complex<f64> {
+    Complex {
+        re: z.re.to_f64(),
+        im: z.im.to_f64(),
+    }
+}
+
+// Transcendental functions, from the pure-Rust libm rather than the standard
+// library's, which calls out to the platform's libm. Those can differ in the
+// last bit between glibc and WASM, which is enough to change a choice made while
+// generating a seeded image, and so the whole image - so everything but custom
+// formulas uses these instead.
+
+pub fn ln(x: f64) -> f64 {
+    libm::log(x)
+}
+
+pub fn log2(x: f64) -> f64 {
+    libm::log2(x)
+}
+
+pub fn exp(x: f64) -> f64 {
+    libm::exp(x)
+}
+
+pub fn sin(x: f64) -> f64 {
+    libm::sin(x)
+}
+
+pub fn cos(x: f64) -> f64 {
+    libm::cos(x)
+}
+
+/// `|z|`, in place of `Complex::norm`.
+pub fn norm(z: Complex<f64>) -> f64 {
+    libm::hypot(z.re, z.im)
+}
+
+/// The angle of `z` from the real axis, in place of `Complex::arg`.
+pub fn arg(z: Complex<f64>) -> f64 {
+    libm::atan2(z.im, z.re)
+}
+
+/// `r * e^(i * theta)`, in place of `Complex::from_polar`.
+pub fn from_polar(r: f64, theta: f64) -> Complex<f64> {
+    Complex {
+        re: r * cos(theta),
+        im: r * sin(theta),
+    }
+}
+
+// Orbits stay within the escape radius of 2 until they escape, so this is the
+// magnitude that rounding errors are relative to
+const ORBIT_SCALE: f64 = 2.0;
+// Bits of precision below the size of a pixel needed to render with f32/f64
+const MIN_SUBPIXEL_BITS_SINGLE: i32 = 16;
+const MIN_SUBPIXEL_BITS_DOUBLE: i32 = 8;
+
+/// The numeric type to render a view with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precision {
+    Single,
+    Double,
+    DoubleDouble,
+}
+
+impl Precision {
+    /// The cheapest precision that can still tell apart points `pixel_size`
+    /// apart on the complex plane.
+    pub fn for_pixel_size(pixel_size: f64) -> Self {
+        let min_pixel_size = |epsilon: f64, bits: i32| ORBIT_SCALE * epsilon * 2.0_f64.powi(bits);
+
+        if pixel_size >= min_pixel_size(f32::EPSILON as f64, MIN_SUBPIXEL_BITS_SINGLE) {
+            Precision::Single
+        } else if pixel_size >= min_pixel_size(f64::EPSILON, MIN_SUBPIXEL_BITS_DOUBLE) {
+            Precision::Double
+        } else {
+            Precision::DoubleDouble
+        }
+    }
+}
+
+/// An unevaluated sum of two f64s, `hi + lo` with `|lo| <= ulp(hi) / 2`,
+/// giving ~106 bits of precision.
+///
+/// Uses the error-free transformations from Dekker/Knuth, as described in
+/// "Library for Double-Double and Quad-Double Arithmetic" (Hida, Li, Bailey).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DoubleDouble {
+    pub hi: f64,
+    pub lo: f64,
+}
+
+impl DoubleDouble {
+    pub fn new(hi: f64, lo: f64) -> Self {
+        let (hi, lo) = quick_two_sum(hi, lo);
+        Self { hi, lo }
+    }
+}
+
+// `a + b` exactly, as a rounded sum and its error. Requires |a| >= |b|
+fn quick_two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    (s, b - (s - a))
+}
+
+// `a + b` exactly, as a rounded sum and its error
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    let bb = s - a;
+    (s, (a - (s - bb)) + (b - bb))
+}
+
+// `a * b` exactly, as a rounded product and its error
+fn two_prod(a: f64, b: f64) -> (f64, f64) {
+    let p = a * b;
+    (p, a.mul_add(b, -p))
+}
+
+impl Add for DoubleDouble {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        let (s, e) = two_sum(self.hi, other.hi);
+        let (t, f) = two_sum(self.lo, other.lo);
+        let (s, e) = quick_two_sum(s, e + t);
+        let (hi, lo) = quick_two_sum(s, e + f);
+
+        Self { hi, lo }
+    }
+}
+
+impl Neg for DoubleDouble {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self {
+            hi: -self.hi,
+            lo: -self.lo,
+        }
+    }
+}
+
+impl Sub for DoubleDouble {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self + -other
+    }
+}
+
+impl Mul for DoubleDouble {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        let (p, e) = two_prod(self.hi, other.hi);
+        let (hi, lo) = quick_two_sum(p, e + (self.hi * other.lo + self.lo * other.hi));
+
+        Self { hi, lo }
+    }
+}
+
+impl Div for DoubleDouble {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Self {
+        // Long division, one f64 "digit" of the quotient at a time
+        let q1 = self.hi / other.hi;
+        let r = self - other * DoubleDouble::from_f64(q1);
+        let q2 = r.hi / other.hi;
+        let r = r - other * DoubleDouble::from_f64(q2);
+        let q3 = r.hi / other.hi;
+
+        DoubleDouble::new(q1, q2) + DoubleDouble::from_f64(q3)
+    }
+}
+
+impl Arithmetic for DoubleDouble {
+    fn abs(self) -> Self {
+        if self.hi < 0.0 {
+            -self
+        } else {
+            self
+        }
+    }
+}
+
+impl RenderFloat for DoubleDouble {
+    fn from_f64(value: f64) -> Self {
+        Self { hi: value, lo: 0.0 }
+    }
+
+    fn to_f64(self) -> f64 {
+        self.hi + self.lo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coloring::ColoringMode;
+    use crate::mandelbrot::tests::view;
+    use crate::mandelbrot::{render_with_options, RenderOptions};
+    use image::RgbaImage;
+
+    #[test]
+    fn error_free_transformations_are_exact() {
+        // The error of 0.1 + 0.2 is exactly representable, as it is for any f64
+        // sum
+        let (sum, error) = two_sum(0.1, 0.2);
+        assert_eq!(sum, 0.30000000000000004);
+        assert_eq!(error, -2.7755575615628914e-17);
+
+        // Below the precision of the larger operand, in either order
+        assert_eq!(two_sum(1.0, 1.0e-20), (1.0, 1.0e-20));
+        assert_eq!(two_sum(1.0e-20, 1.0), (1.0, 1.0e-20));
+
+        // (1 + 2^-30)^2 = 1 + 2^-29 + 2^-60, the last of which rounds off
+        let a = 1.0 + 2.0_f64.powi(-30);
+        assert_eq!(two_prod(a, a), (1.0 + 2.0_f64.powi(-29), 2.0_f64.powi(-60)));
+
+        assert_eq!(DoubleDouble::new(1.0, 2.0_f64.powi(-60)).to_f64(), 1.0);
+        assert_eq!(DoubleDouble::new(1.0, 0.25).to_f64(), 1.25);
+    }
+
+    #[test]
+    fn double_doubles_keep_what_f64s_lose() {
+        let one = DoubleDouble::from_f64(1.0);
+        let tiny = DoubleDouble::from_f64(1.0e-30);
+
+        assert_eq!(one + tiny, DoubleDouble::new(1.0, 1.0e-30));
+        assert_eq!((one + tiny) - one, tiny);
+
+        let a = DoubleDouble::from_f64(1.0 + 2.0_f64.powi(-30));
+        assert_eq!(
+            a * a,
+            DoubleDouble::new(1.0 + 2.0_f64.powi(-29), 2.0_f64.powi(-60))
+        );
+
+        let third = one / DoubleDouble::from_f64(3.0);
+        assert!((third * DoubleDouble::from_f64(3.0) - one).to_f64().abs() < 1.0e-31);
+    }
+
+    #[test]
+    fn precision_follows_pixel_size() {
+        assert_eq!(Precision::for_pixel_size(0.1), Precision::Single);
+        assert_eq!(Precision::for_pixel_size(1.0e-3), Precision::Double);
+        assert_eq!(Precision::for_pixel_size(1.0e-12), Precision::Double);
+        assert_eq!(Precision::for_pixel_size(1.0e-14), Precision::DoubleDouble);
+        assert_eq!(Precision::for_pixel_size(1.0e-20), Precision::DoubleDouble);
+    }
+
+    // Pairs of neighboring columns that are exactly the same, as happens when
+    // pixels are closer together than the points they map to can be
+    fn repeated_columns(image: &RgbaImage) -> usize {
+        (1..image.width())
+            .filter(|&x| {
+                (0..image.height()).all(|y| image.get_pixel(x - 1, y) == image.get_pixel(x, y))
+            })
+            .count()
+    }
+
+    #[test]
+    fn double_doubles_render_past_f64_precision() {
+        // Around the tip of the antenna, with pixels several times smaller
+        // than the spacing of f64s there
+        let (re, im, half_extent) = (-2.0, 0.0, 1.0e-15);
+        let mut deep = view(
+            (re - half_extent, im + half_extent),
+            (re + half_extent, im - half_extent),
+            (32, 32),
+            1000,
+        );
+        deep.coloring = ColoringMode::Smooth;
+        // Custom formulas always render with f64s
+        let mut plain = deep.clone();
+        plain.set_custom_formula("z^2 + c").unwrap();
+
+        let options = RenderOptions::default();
+        let deep = render_with_options(&deep, &options).unwrap();
+        let plain = render_with_options(&plain, &options).unwrap();
+
+        assert_ne!(deep, plain);
+        assert!(
+            repeated_columns(&plain) > 16,
+            "{}",
+            repeated_columns(&plain)
+        );
+        assert_eq!(repeated_columns(&deep), 0);
+    }
+}
@@ -0,0 +1,340 @@
+use image::Rgba;
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+
+/// Maximum number of colors in a `PaletteKind::Custom` gradient, to keep
+/// tokens short.
+pub const MAX_CUSTOM_COLORS: usize = 8;
+
+/// A color at a position in `[0, 1)` along a gradient.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorStop {
+    pub position: f64,
+    pub color: (u8, u8, u8),
+}
+
+const fn stop(position: f64, color: (u8, u8, u8)) -> ColorStop {
+    ColorStop { position, color }
+}
+
+const CLASSIC: [ColorStop; 5] = [
+    stop(0.0, (0, 7, 100)),
+    stop(0.16, (32, 107, 203)),
+    stop(0.42, (237, 255, 255)),
+    stop(0.6425, (255, 170, 0)),
+    stop(0.8575, (0, 2, 0)),
+];
+
+const FIRE: [ColorStop; 5] = [
+    stop(0.0, (0, 0, 0)),
+    stop(0.2, (128, 0, 0)),
+    stop(0.4, (255, 64, 0)),
+    stop(0.6, (255, 200, 0)),
+    stop(0.8, (255, 255, 200)),
+];
+
+const OCEAN: [ColorStop; 5] = [
+    stop(0.0, (2, 4, 40)),
+    stop(0.2, (0, 60, 120)),
+    stop(0.4, (0, 140, 170)),
+    stop(0.6, (120, 220, 220)),
+    stop(0.8, (240, 255, 255)),
+];
+
+const VIRIDIS: [ColorStop; 5] = [
+    stop(0.0, (68, 1, 84)),
+    stop(0.2, (59, 82, 139)),
+    stop(0.4, (33, 145, 140)),
+    stop(0.6, (94, 201, 98)),
+    stop(0.8, (253, 231, 37)),
+];
+
+const MAGMA: [ColorStop; 5] = [
+    stop(0.0, (0, 0, 4)),
+    stop(0.2, (81, 18, 124)),
+    stop(0.4, (183, 55, 121)),
+    stop(0.6, (252, 137, 97)),
+    stop(0.8, (252, 253, 191)),
+];
+
+const SUNSET: [ColorStop; 5] = [
+    stop(0.0, (40, 10, 60)),
+    stop(0.2, (140, 30, 90)),
+    stop(0.4, (230, 80, 60)),
+    stop(0.6, (250, 170, 60)),
+    stop(0.8, (255, 230, 150)),
+];
+
+const FOREST: [ColorStop; 4] = [
+    stop(0.0, (10, 30, 10)),
+    stop(0.25, (30, 90, 40)),
+    stop(0.5, (120, 160, 60)),
+    stop(0.75, (220, 210, 120)),
+];
+
+const ICE: [ColorStop; 4] = [
+    stop(0.0, (10, 20, 40)),
+    stop(0.25, (60, 110, 170)),
+    stop(0.5, (170, 220, 240)),
+    stop(0.75, (255, 255, 255)),
+];
+
+const GRAYSCALE: [ColorStop; 2] = [stop(0.0, (0, 0, 0)), stop(0.5, (255, 255, 255))];
+
+/// A gradient to color with - either one of the named built-in gradients, or
+/// a custom list of evenly spaced colors.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PaletteKind {
+    Classic,
+    Fire,
+    Ocean,
+    Viridis,
+    Magma,
+    Sunset,
+    Forest,
+    Ice,
+    Grayscale,
+    Custom(Vec<(u8, u8, u8)>),
+}
+
+impl PaletteKind {
+    /// All of the built-in (named) palettes.
+    pub const BUILTIN: [PaletteKind; 9] = [
+        PaletteKind::Classic,
+        PaletteKind::Fire,
+        PaletteKind::Ocean,
+        PaletteKind::Viridis,
+        PaletteKind::Magma,
+        PaletteKind::Sunset,
+        PaletteKind::Forest,
+        PaletteKind::Ice,
+        PaletteKind::Grayscale,
+    ];
+
+    pub fn gradient(&self) -> Gradient {
+        let stops = match self {
+            PaletteKind::Classic => CLASSIC.to_vec(),
+            PaletteKind::Fire => FIRE.to_vec(),
+            PaletteKind::Ocean => OCEAN.to_vec(),
+            PaletteKind::Viridis => VIRIDIS.to_vec(),
+            PaletteKind::Magma => MAGMA.to_vec(),
+            PaletteKind::Sunset => SUNSET.to_vec(),
+            PaletteKind::Forest => FOREST.to_vec(),
+            PaletteKind::Ice => ICE.to_vec(),
+            PaletteKind::Grayscale => GRAYSCALE.to_vec(),
+            PaletteKind::Custom(colors) => colors
+                .iter()
+                .enumerate()
+                .map(|(i, color)| stop(i as f64 / colors.len() as f64, *color))
+                .collect(),
+        };
+
+        Gradient { stops }
+    }
+}
+
+/// The palette settings stored in `ImageParams`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Palette {
+    pub kind: PaletteKind,
+    /// Number of iterations it takes to go once through the gradient, after
+    /// which it repeats.
+    pub cycle_length: u16,
+    /// Where in the gradient to start, in 1/256ths of the gradient.
+    pub offset: u8,
+}
+
+impl Palette {
+    /// Check the palette can be rendered, as palettes are read from
+    /// user-supplied tokens.
+    pub fn validate(&self) -> Result<(), errors::InvalidImageParams> {
+        if self.cycle_length == 0 {
+            return Err(errors::InvalidImageParams::Default {
+                message: "palette cycle length must be at least 1".to_string(),
+            });
+        }
+
+        if let PaletteKind::Custom(colors) = &self.kind {
+            if colors.is_empty() || colors.len() > MAX_CUSTOM_COLORS {
+                return Err(errors::InvalidImageParams::Default {
+                    message: format!(
+                        "custom palettes must have from 1 to {} colors",
+                        MAX_CUSTOM_COLORS
+                    ),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The position in the gradient for a (possibly fractional) iteration
+    /// count.
+    pub fn position(&self, iterations: f64) -> f64 {
+        iterations / self.cycle_length.max(1) as f64 + self.offset as f64 / 256.0
+    }
+}
+
+/// A cyclic gradient of color stops, sorted by position. Positions wrap around
+/// at 1.0, and the last stop blends back into the first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gradient {
+    stops: Vec<ColorStop>,
+}
+
+impl Gradient {
+    /// Sample the gradient at `position`, which is wrapped into `[0, 1)`.
+    pub fn sample(&self, position: f64) -> Rgba<u8> {
+        let (first, last) = match (self.stops.first(), self.stops.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Rgba([0, 0, 0, 255]),
+        };
+
+        let t = position.rem_euclid(1.0);
+
+        // Find the stops on either side of `t`, wrapping past the ends
+        let (from, to) = if t < first.position {
+            (
+                stop(last.position - 1.0, last.color),
+                stop(first.position, first.color),
+            )
+        } else {
+            let i = self
+                .stops
+                .iter()
+                .rposition(|s| s.position <= t)
+                .unwrap_or(0);
+
+            match self.stops.get(i + 1) {
+                Some(next) => (self.stops[i], *next),
+                None => (*last, stop(first.position + 1.0, first.color)),
+            }
+        };
+
+        let span = to.position - from.position;
+        let f = if span > 0.0 {
+            (t - from.position) / span
+        } else {
+            0.0
+        };
+        let lerp = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * f).round() as u8;
+
+        Rgba([
+            lerp(from.color.0, to.color.0),
+            lerp(from.color.1, to.color.1),
+            lerp(from.color.2, to.color.2),
+            255,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+    fn palette(kind: PaletteKind, cycle_length: u16, offset: u8) -> Palette {
+        Palette {
+            kind,
+            cycle_length,
+            offset,
+        }
+    }
+
+    #[test]
+    fn samples_between_stops() {
+        let gradient = PaletteKind::Grayscale.gradient();
+
+        assert_eq!(gradient.sample(0.0), BLACK);
+        assert_eq!(gradient.sample(0.5), WHITE);
+        assert_eq!(gradient.sample(0.25), Rgba([128, 128, 128, 255]));
+
+        // Every built-in gradient starts at its first stop
+        for kind in PaletteKind::BUILTIN {
+            let first = kind.gradient().stops[0].color;
+            assert_eq!(
+                kind.gradient().sample(0.0),
+                Rgba([first.0, first.1, first.2, 255])
+            );
+        }
+    }
+
+    #[test]
+    fn samples_wrap_around() {
+        let gradient = PaletteKind::Grayscale.gradient();
+
+        // The last stop blends back into the first
+        assert_eq!(gradient.sample(0.75), Rgba([128, 128, 128, 255]));
+        for position in [0.0, 0.3, 0.75] {
+            assert_eq!(gradient.sample(position + 1.0), gradient.sample(position));
+            assert_eq!(gradient.sample(position - 1.0), gradient.sample(position));
+            assert_eq!(gradient.sample(position + 7.0), gradient.sample(position));
+        }
+        assert_eq!(gradient.sample(1.0), BLACK);
+
+        // Before the first stop, blending in from the last
+        let gradient = Gradient {
+            stops: vec![stop(0.25, (255, 0, 0)), stop(0.75, (0, 0, 255))],
+        };
+        assert_eq!(gradient.sample(0.0), Rgba([128, 0, 128, 255]));
+        assert_eq!(gradient.sample(0.25), Rgba([255, 0, 0, 255]));
+        assert_eq!(gradient.sample(0.875), Rgba([64, 0, 191, 255]));
+    }
+
+    #[test]
+    fn custom_colors_are_evenly_spaced() {
+        let gradient = PaletteKind::Custom(vec![(0, 0, 0), (255, 255, 255)]).gradient();
+
+        assert_eq!(gradient.sample(0.0), BLACK);
+        assert_eq!(gradient.sample(0.5), WHITE);
+        assert_eq!(gradient.sample(0.75), Rgba([128, 128, 128, 255]));
+
+        let single = PaletteKind::Custom(vec![(10, 20, 30)]).gradient();
+        for position in [0.0, 0.4, 0.99] {
+            assert_eq!(single.sample(position), Rgba([10, 20, 30, 255]));
+        }
+    }
+
+    #[test]
+    fn positions_follow_cycle_length_and_offset() {
+        let plain = palette(PaletteKind::Classic, 10, 0);
+        assert_eq!(plain.position(0.0), 0.0);
+        assert_eq!(plain.position(5.0), 0.5);
+        assert_eq!(plain.position(25.0), 2.5);
+
+        // An offset of 128/256ths starts halfway through the gradient
+        let offset = palette(PaletteKind::Classic, 10, 128);
+        assert_eq!(offset.position(0.0), 0.5);
+        assert_eq!(offset.position(5.0), 1.0);
+
+        // Sampling a cycle length further along gives the same color
+        let gradient = PaletteKind::Classic.gradient();
+        for iterations in [0.0, 3.0, 7.5] {
+            assert_eq!(
+                gradient.sample(offset.position(iterations)),
+                gradient.sample(offset.position(iterations + 10.0))
+            );
+        }
+    }
+
+    #[test]
+    fn validate_limits_custom_colors() {
+        for kind in PaletteKind::BUILTIN {
+            assert!(palette(kind, 64, 0).validate().is_ok());
+        }
+        assert!(palette(PaletteKind::Classic, 0, 0).validate().is_err());
+
+        for count in 0..=MAX_CUSTOM_COLORS + 1 {
+            let custom = palette(PaletteKind::Custom(vec![(1, 2, 3); count]), 64, 0);
+            assert_eq!(
+                custom.validate().is_ok(),
+                (1..=MAX_CUSTOM_COLORS).contains(&count),
+                "{} colors",
+                count
+            );
+        }
+    }
+}
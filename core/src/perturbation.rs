@@ -0,0 +1,404 @@
+use num::bigint::{BigInt, Sign};
+use num::{Complex, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+use crate::numeric;
+
+/// Smallest half-width/height of a deep zoom view. Pixel offsets from the
+/// center are still plain f64s, which need to stay well clear of underflow.
+pub const MIN_DEEP_ZOOM_EXTENT: f64 = 1.0e-250;
+/// Maximum number of digits in each part of a `DeepZoom` center: one before
+/// the point, and enough after it to place the center within a pixel of the
+/// smallest view at `MAX_OUTPUT_DIMENSION` (250 + 4).
+pub const MAX_CENTER_DIGITS: usize = 255;
+/// Largest half-width/height of a deep zoom view (the whole set).
+pub const MAX_DEEP_ZOOM_EXTENT: f64 = 4.0;
+
+// Extra bits of precision kept past what's needed to tell pixels apart
+const GUARD_BITS: u32 = 64;
+
+/// A view centered on a point given to arbitrary precision, for zooming past
+/// what the f64 `upper_left`/`lower_right` corners can represent.
+///
+/// Rendered with perturbation theory: one orbit is iterated at full precision
+/// at the center, and each pixel only iterates its (f64) offset from it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeepZoom {
+    /// Real part of the center, as a decimal string, e.g. "-1.7400623825".
+    pub center_re: String,
+    /// Imaginary part of the center, as a decimal string.
+    pub center_im: String,
+    /// Half of the width of the view on the complex plane.
+    pub half_width: f64,
+    /// Half of the height of the view on the complex plane.
+    pub half_height: f64,
+}
+
+impl DeepZoom {
+    /// Check the view can be rendered, as it's read from user-supplied tokens.
+    pub fn validate(&self) -> Result<(), errors::InvalidImageParams> {
+        for extent in [self.half_width, self.half_height] {
+            if !(MIN_DEEP_ZOOM_EXTENT..=MAX_DEEP_ZOOM_EXTENT).contains(&extent) {
+                return Err(errors::InvalidImageParams::Default {
+                    message: format!(
+                        "deep zoom extents must be from {:e} to {}",
+                        MIN_DEEP_ZOOM_EXTENT, MAX_DEEP_ZOOM_EXTENT
+                    ),
+                });
+            }
+        }
+
+        self.center().map(|_| ())
+    }
+
+    /// Bits of fractional precision needed to iterate the center orbit.
+    pub fn precision_bits(&self) -> u32 {
+        let extent = self.half_width.min(self.half_height);
+        (-numeric::log2(extent)).max(0.0).ceil() as u32 + GUARD_BITS
+    }
+
+    /// The center, rounded to f64.
+    pub fn center_f64(&self) -> Result<Complex<f64>, errors::InvalidImageParams> {
+        let (re, im) = self.center()?;
+
+        Ok(Complex {
+            re: re.to_f64(),
+            im: im.to_f64(),
+        })
+    }
+
+    /// Corners of the view relative to its center, to map pixels to offsets
+    /// from the center rather than to absolute points.
+    pub fn offset_corners(&self) -> (Complex<f64>, Complex<f64>) {
+        (
+            Complex {
+                re: -self.half_width,
+                im: self.half_height,
+            },
+            Complex {
+                re: self.half_width,
+                im: -self.half_height,
+            },
+        )
+    }
+
+    fn center(&self) -> Result<(FixedPoint, FixedPoint), errors::InvalidImageParams> {
+        let frac_bits = self.precision_bits();
+        let parse = |s: &str| {
+            FixedPoint::parse_decimal(s, frac_bits).ok_or_else(|| {
+                errors::InvalidImageParams::Default {
+                    message: format!(
+                        "deep zoom center must be a decimal of at most {} digits",
+                        MAX_CENTER_DIGITS
+                    ),
+                }
+            })
+        };
+
+        Ok((parse(&self.center_re)?, parse(&self.center_im)?))
+    }
+}
+
+/// A signed fixed-point number with `frac_bits` bits after the binary point.
+#[derive(Clone, Debug, PartialEq)]
+struct FixedPoint {
+    mantissa: BigInt,
+    frac_bits: u32,
+}
+
+impl FixedPoint {
+    fn zero(frac_bits: u32) -> Self {
+        Self {
+            mantissa: BigInt::zero(),
+            frac_bits,
+        }
+    }
+
+    /// Parse a plain decimal string like "-0.75", rounding to `frac_bits`.
+    fn parse_decimal(s: &str, frac_bits: u32) -> Option<Self> {
+        let (negative, digits) = match s.trim().strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.trim()),
+        };
+        let (int_digits, frac_digits) = digits.split_once('.').unwrap_or((digits, ""));
+
+        let all_digits = format!("{}{}", int_digits, frac_digits);
+        if all_digits.is_empty()
+            || all_digits.len() > MAX_CENTER_DIGITS
+            || !all_digits.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+
+        // value = digits / 10^k, so mantissa = digits * 2^frac_bits / 10^k
+        let numerator = all_digits.parse::<BigInt>().ok()? << frac_bits as usize;
+        let denominator = num::pow(BigInt::from(10), frac_digits.len());
+        let mantissa: BigInt = (numerator + &denominator / 2) / &denominator;
+
+        Some(Self {
+            mantissa: if negative { -mantissa } else { mantissa },
+            frac_bits,
+        })
+    }
+
+    fn to_f64(&self) -> f64 {
+        // Keep the top 64 bits, so huge mantissas don't overflow the conversion
+        let shift = self.mantissa.bits().saturating_sub(64);
+        let top = (&self.mantissa >> shift as usize).to_f64().unwrap_or(0.0);
+
+        // Scale in two steps, as 2^exp alone can underflow for large frac_bits
+        let exp = shift as i32 - self.frac_bits as i32;
+        top * 2.0_f64.powi(exp / 2) * 2.0_f64.powi(exp - exp / 2)
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        Self {
+            mantissa: &self.mantissa + &other.mantissa,
+            frac_bits: self.frac_bits,
+        }
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        Self {
+            mantissa: &self.mantissa - &other.mantissa,
+            frac_bits: self.frac_bits,
+        }
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        let product = &self.mantissa * &other.mantissa;
+        // Round to nearest, rather than towards negative infinity
+        let half = BigInt::from(1) << (self.frac_bits as usize).saturating_sub(1);
+        let mantissa = match product.sign() {
+            Sign::Minus => -((-product + half) >> self.frac_bits as usize),
+            _ => (product + half) >> self.frac_bits as usize,
+        };
+
+        Self {
+            mantissa,
+            frac_bits: self.frac_bits,
+        }
+    }
+}
+
+/// The orbit of the center of a `DeepZoom`, iterated at full precision and
+/// then rounded to f64 for perturbing each pixel against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReferenceOrbit {
+    /// Z_0 (= 0) through to the last iteration before escaping or the limit.
+    orbit: Vec<Complex<f64>>,
+}
+
+impl ReferenceOrbit {
+    pub fn new(
+        deep_zoom: &DeepZoom,
+        limit: usize,
+        bailout_sqr: f64,
+    ) -> Result<Self, errors::InvalidImageParams> {
+        let (c_re, c_im) = deep_zoom.center()?;
+        let frac_bits = c_re.frac_bits;
+
+        let mut z_re = FixedPoint::zero(frac_bits);
+        let mut z_im = FixedPoint::zero(frac_bits);
+        let mut orbit = Vec::with_capacity(limit + 1);
+
+        for _ in 0..=limit {
+            let z = Complex {
+                re: z_re.to_f64(),
+                im: z_im.to_f64(),
+            };
+            orbit.push(z);
+
+            if z.norm_sqr() > bailout_sqr {
+                break;
+            }
+
+            // z = z^2 + c
+            let re_sqr = z_re.mul(&z_re);
+            let im_sqr = z_im.mul(&z_im);
+            let re_im = z_re.mul(&z_im);
+
+            z_re = re_sqr.sub(&im_sqr).add(&c_re);
+            z_im = re_im.add(&re_im).add(&c_im);
+        }
+
+        Ok(Self { orbit })
+    }
+
+    /// `escape_time` for the point at offset `dc` from the center, returning
+    /// the escape count and the first (full) value of the orbit outside the
+    /// bailout radius, or `None` for a (likely) member of the set.
+    ///
+    /// Iterates the offset `dz` of the pixel's orbit from the reference orbit
+    /// `Z`, as `dz' = 2 Z dz + dz^2 + dc`. Precision is lost (a "glitch") once
+    /// the full orbit `Z + dz` gets closer to 0 than `dz` itself, or when the
+    /// reference has escaped - in either case, the pixel is rebased onto the
+    /// start of the reference orbit, with its full value as the new offset.
+    ///
+    /// `step` is called with each full value of the orbit that didn't escape,
+    /// before iterating it, for tracking anything else about the orbit.
+    pub fn escape_time(
+        &self,
+        dc: Complex<f64>,
+        limit: usize,
+        bailout_sqr: f64,
+        mut step: impl FnMut(Complex<f64>),
+    ) -> Option<(usize, Complex<f64>)> {
+        let mut dz = Complex { re: 0.0, im: 0.0 };
+        let mut ref_i = 0;
+
+        for i in 0..limit {
+            let z = self.orbit[ref_i] + dz;
+            let z_norm_sqr = z.norm_sqr();
+
+            if z_norm_sqr > bailout_sqr {
+                return Some((i, z));
+            }
+
+            step(z);
+
+            if z_norm_sqr < dz.norm_sqr() || ref_i + 1 >= self.orbit.len() {
+                dz = z;
+                ref_i = 0;
+            }
+
+            dz = 2.0 * self.orbit[ref_i] * dz + dz * dz + dc;
+            ref_i += 1;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_params::MAX_OUTPUT_DIMENSION;
+    use crate::mandelbrot::tests::view;
+    use crate::mandelbrot::{color_distance, render_with_options, RenderOptions};
+
+    // A point in seahorse valley, to more digits than an f64 holds
+    const SEAHORSE_CENTER: (&str, &str) = (
+        "-0.743643887037158704752191506114774",
+        "0.131825904205311970493132056385139",
+    );
+
+    fn deep_zoom(center: (&str, &str), half_extent: f64) -> DeepZoom {
+        DeepZoom {
+            center_re: center.0.to_string(),
+            center_im: center.1.to_string(),
+            half_width: half_extent,
+            half_height: half_extent,
+        }
+    }
+
+    // The escape count of `c`, iterating it directly in f64s
+    fn plain_escape_time(c: Complex<f64>, limit: usize, bailout_sqr: f64) -> Option<usize> {
+        let mut z = Complex { re: 0.0, im: 0.0 };
+        for i in 0..limit {
+            if z.norm_sqr() > bailout_sqr {
+                return Some(i);
+            }
+            z = z * z + c;
+        }
+
+        None
+    }
+
+    #[test]
+    fn matches_plain_render_within_f64_precision() {
+        let half_extent = 1.0e-6;
+        let deep_zoom = deep_zoom(SEAHORSE_CENTER, half_extent);
+        let center = deep_zoom.center_f64().unwrap();
+
+        let plain = view(
+            (center.re - half_extent, center.im + half_extent),
+            (center.re + half_extent, center.im - half_extent),
+            (48, 48),
+            1000,
+        );
+        let mut deep = plain.clone();
+        deep.set_deep_zoom(deep_zoom).unwrap();
+
+        let options = RenderOptions::default();
+        let plain = render_with_options(&plain, &options).unwrap();
+        let deep = render_with_options(&deep, &options).unwrap();
+
+        // Escape counts right on the boundary between two bands can round
+        // either way
+        let differing = plain
+            .pixels()
+            .zip(deep.pixels())
+            .filter(|(a, b)| color_distance(a, b) > 0)
+            .count();
+        let pixels = (plain.width() * plain.height()) as usize;
+        assert!(
+            differing * 100 <= pixels,
+            "{} of {} pixels differ",
+            differing,
+            pixels
+        );
+    }
+
+    #[test]
+    fn rebases_once_the_reference_escapes() {
+        let (limit, bailout_sqr) = (1000, 4.0);
+        // Outside of the set, so the reference orbit only lasts a few
+        // iterations
+        let reference =
+            ReferenceOrbit::new(&deep_zoom(("0.5", "0"), 1.0), limit, bailout_sqr).unwrap();
+        assert!(reference.orbit.len() < 10);
+
+        let center = Complex { re: 0.5, im: 0.0 };
+        for c in [
+            // In the main cardioid and period 2 bulb
+            Complex { re: 0.0, im: 0.0 },
+            Complex { re: -1.0, im: 0.0 },
+            // Escaping long after the reference does
+            Complex { re: 0.26, im: 0.0 },
+            Complex {
+                re: -0.75,
+                im: 0.05,
+            },
+        ] {
+            let escaped = reference.escape_time(c - center, limit, bailout_sqr, |_| ());
+            assert_eq!(
+                escaped.map(|(count, _)| count),
+                plain_escape_time(c, limit, bailout_sqr),
+                "{}",
+                c
+            );
+        }
+    }
+
+    #[test]
+    fn center_digits_reach_the_smallest_extent() {
+        let smallest_pixel = MIN_DEEP_ZOOM_EXTENT / MAX_OUTPUT_DIMENSION as f64;
+        let finest_digit = 10.0_f64.powi(1 - MAX_CENTER_DIGITS as i32);
+        assert!(finest_digit <= smallest_pixel);
+
+        let center = format!("-0.{}", "7".repeat(MAX_CENTER_DIGITS - 1));
+        assert!(deep_zoom((&center, &center), MIN_DEEP_ZOOM_EXTENT)
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_bad_centers_and_extents() {
+        assert!(deep_zoom(SEAHORSE_CENTER, 1.0e-20).validate().is_ok());
+
+        let too_long = format!("0.{}", "1".repeat(MAX_CENTER_DIGITS));
+        for center_re in [
+            "", "-", ".", "1.2.3", "1e-5", "--1", "0x1", "1,5", &too_long,
+        ] {
+            let deep_zoom = deep_zoom((center_re, "0"), 1.0e-20);
+            assert!(deep_zoom.validate().is_err(), "{:?}", center_re);
+        }
+
+        for half_extent in [0.0, -1.0e-20, 1.0e-300, 5.0, f64::NAN, f64::INFINITY] {
+            let deep_zoom = deep_zoom(SEAHORSE_CENTER, half_extent);
+            assert!(deep_zoom.validate().is_err(), "{}", half_extent);
+        }
+    }
+}
@@ -1,9 +1,10 @@
 use crate::errors;
-use crate::image_params::{ImagePostProcessConfig, OverlayImageTypes, OUTPUT_HEIGHT, OUTPUT_WIDTH};
+use crate::image_params::{ImagePostProcessConfig, OverlayImageTypes};
 
 use image::codecs::png::PngEncoder;
 use image::DynamicImage;
 use image::GenericImageView;
+use image::RgbaImage;
 use image::{self, imageops, ColorType, GenericImage, ImageEncoder, ImageFormat, Pixel};
 
 pub fn process_from_params(
@@ -14,9 +15,11 @@ pub fn process_from_params(
 
     if let Some(overlay_image_type) = q_params.overlay_image_type {
         match overlay_image_type {
-            OverlayImageTypes::Profile { width, height } => {
-                let profile_overlay_buf = match (width, height) {
-                    (300, 300) => include_bytes!("../assets/profile_overlay_300x300.png").to_vec(),
+            OverlayImageTypes::Profile { .. } => {
+                let profile_overlay_buf = match overlay_image_type.asset_name() {
+                    "profile_overlay_300x300" => {
+                        include_bytes!("../assets/profile_overlay_300x300.png").to_vec()
+                    }
                     _ => include_bytes!("../assets/profile_overlay_600x600.png").to_vec(),
                 };
 
@@ -53,35 +56,26 @@ where
     J::Pixel: 'static,
     <<J as GenericImageView>::Pixel as Pixel>::Subpixel: 'static,
 {
+    let (base_w, base_h) = base.dimensions();
     let (dim_w, dim_h) = top.dimensions();
 
     // Resize overlay image if dimensions do not match
-    if dim_w != OUTPUT_WIDTH as u32 || dim_h != OUTPUT_HEIGHT as u32 {
+    if dim_w != base_w || dim_h != base_h {
         // Note: FilterType `Nearest` is chosen as the fastest here, although resolution is better on "Gaussian"
-        let top = imageops::resize(
-            top,
-            OUTPUT_WIDTH as u32,
-            OUTPUT_HEIGHT as u32,
-            imageops::FilterType::Nearest,
-        );
+        let top = imageops::resize(top, base_w, base_h, imageops::FilterType::Nearest);
         imageops::overlay(base, &top, 0, 0);
     } else {
         imageops::overlay(base, top, 0, 0);
     }
 }
 
-pub fn encode_result_png(buf: &[u8]) -> Result<Vec<u8>, errors::ImagePostProcessingError> {
+pub fn encode_result_png(image: &RgbaImage) -> Result<Vec<u8>, errors::ImagePostProcessingError> {
     let mut buffer = vec![];
     let encoder = PngEncoder::new(&mut buffer);
 
     // Write image_buffer to result buffer
     encoder
-        .write_image(
-            buf,
-            OUTPUT_WIDTH as u32,
-            OUTPUT_HEIGHT as u32,
-            ColorType::Rgba8,
-        )
+        .write_image(image, image.width(), image.height(), ColorType::Rgba8)
         .map_err(|e| errors::ImagePostProcessingError::Default {
             message: format!("failed to write to result image buffer: {}", e),
         })?;
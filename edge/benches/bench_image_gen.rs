@@ -1,12 +1,49 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
-use enumflags2::make_bitflags;
+use enumflags2::{make_bitflags, BitFlags};
 use image::ImageFormat;
+use mandelatar_core::coloring::{ColoringMode, InteriorColoring};
+use mandelatar_core::fractal::{Formula, FractalKind};
 use mandelatar_core::image_params::{
-    ImageParams, ImageTransformFlags, OUTPUT_HEIGHT, OUTPUT_WIDTH,
+    Antialiasing, ImageParams, ImageTransformFlags, IterationLimit, OUTPUT_HEIGHT, OUTPUT_WIDTH,
+};
+use mandelatar_core::mandelbrot::{
+    create_png, create_png_with_options, RenderOptions, RenderStrategy,
 };
-use mandelatar_core::mandelbrot::create_png;
 use mandelatar_core::post_processing;
+use num::Complex;
+
+// A fixed view of the Mandelbrot set, so benchmarks compare the same image
+// from run to run
+fn view(upper_left: (f64, f64), lower_right: (f64, f64), limit: u32) -> ImageParams {
+    ImageParams {
+        bounds: (OUTPUT_WIDTH, OUTPUT_HEIGHT),
+        upper_left: Complex::new(upper_left.0, upper_left.1),
+        lower_right: Complex::new(lower_right.0, lower_right.1),
+        zoom_factor: 1.0,
+        rgb_consts: (83, 151, 229),
+        transform_flags: BitFlags::EMPTY,
+        coloring: ColoringMode::EscapeTime,
+        palette: None,
+        iteration_limit: IterationLimit::Fixed(limit),
+        bailout_radius: None,
+        antialiasing: Antialiasing::Off,
+        deep_zoom: None,
+        fractal: FractalKind::Mandelbrot,
+        formula: Formula::Quadratic,
+        custom_formula: None,
+        interior: InteriorColoring::Fixed,
+        transparent_band: None,
+    }
+}
+
+fn whole_set() -> ImageParams {
+    view((-2.2, 1.5), (0.8, -1.5), 1000)
+}
+
+fn seahorse_valley() -> ImageParams {
+    view((-0.7456, 0.1338), (-0.7416, 0.1298), 1000)
+}
 
 pub fn bench_image_no_post_proc(c: &mut Criterion) {
     let mut iparams = ImageParams::new_from_rand((OUTPUT_WIDTH, OUTPUT_HEIGHT));
@@ -40,9 +77,67 @@ pub fn bench_image_post_proc_profile_overlay(c: &mut Criterion) {
     });
 }
 
+// Render interior-heavy images with and without the interior checks, which
+// give identical images
+pub fn bench_interior_checks(c: &mut Criterion) {
+    let whole_set = whole_set();
+
+    let mut julia = whole_set.clone();
+    julia.fractal = FractalKind::Julia {
+        c: Complex {
+            re: -0.12,
+            im: 0.75,
+        },
+    };
+    julia.upper_left = Complex { re: -1.6, im: 1.6 };
+    julia.lower_right = Complex { re: 1.6, im: -1.6 };
+
+    let with_checks = RenderOptions::default();
+    let without_checks = RenderOptions {
+        interior_checks: false,
+        ..with_checks
+    };
+
+    let mut group = c.benchmark_group("interior checks");
+
+    for (name, iparams) in [("whole set", &whole_set), ("julia set", &julia)] {
+        group.bench_function(format!("{} without checks", name), |b| {
+            b.iter(|| create_png_with_options(black_box(iparams), &without_checks))
+        });
+        group.bench_function(format!("{} with checks", name), |b| {
+            b.iter(|| create_png_with_options(black_box(iparams), &with_checks))
+        });
+    }
+
+    group.finish();
+}
+
+pub fn bench_render_strategy(c: &mut Criterion) {
+    let seahorse_valley = seahorse_valley();
+
+    let per_pixel = RenderOptions::default();
+    let subdivide = RenderOptions {
+        strategy: RenderStrategy::Subdivide,
+        ..per_pixel
+    };
+
+    let mut group = c.benchmark_group("render strategy");
+
+    group.bench_function("seahorse valley per pixel", |b| {
+        b.iter(|| create_png_with_options(black_box(&seahorse_valley), &per_pixel))
+    });
+    group.bench_function("seahorse valley subdivided", |b| {
+        b.iter(|| create_png_with_options(black_box(&seahorse_valley), &subdivide))
+    });
+
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_image_no_post_proc,
-    bench_image_post_proc_profile_overlay
+    bench_image_post_proc_profile_overlay,
+    bench_interior_checks,
+    bench_render_strategy
 );
 criterion_main!(benches);
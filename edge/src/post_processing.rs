@@ -12,11 +12,11 @@ pub async fn process_from_params(
 ) -> Result<Vec<u8>, errors::ImagePostProcessingError> {
     let mut base = core_pp::load_img_from_buffer("base image", image, ImageFormat::Png)?;
 
-    if let Some(OverlayImageTypes::Profile { width, height }) = pp_config.overlay_image_type {
-        let profile_overlay_buf = match (width, height) {
-            (300, 300) => load_img_buf_from_kv_store(kv_store, "profile_overlay_300x300").await?,
-            _ => load_img_buf_from_kv_store(kv_store, "profile_overlay_600x600").await?,
-        };
+    if let Some(overlay_image_type @ OverlayImageTypes::Profile { .. }) =
+        pp_config.overlay_image_type
+    {
+        let profile_overlay_buf =
+            load_img_buf_from_kv_store(kv_store, overlay_image_type.asset_name()).await?;
 
         let top = core_pp::load_img_from_buffer(
             "profile_overlay",
@@ -7,12 +7,63 @@ use crate::post_processing;
 use mandelatar_core::image_params;
 use mandelatar_core::mandelbrot;
 
-const MAX_B64_LEN: usize = 500;
+// Most `ImageParams::render_work` to render in the worker, beyond which the
+// request is passed on to the origin server
+const MAX_WORKER_RENDER_WORK: u64 = image_params::DEFAULT_RAND_MAX_WORK;
+// Random images scored before settling for the best, by default. Fewer than
+// the origin server, as each one is rendered as a preview within the worker's
+// CPU time limit
+const WORKER_MAX_RAND_ATTEMPTS: usize = 2;
 
 type ApiResult<T, E> = std::result::Result<T, E>;
 
+// The var `name` parsed as a `T`, or `default` when it's unset or invalid
+fn parse_var<D, T>(ctx: &RouteContext<D>, name: &str, default: T) -> T
+where
+    T: std::str::FromStr + std::fmt::Display,
+    T::Err: std::fmt::Display,
+{
+    match ctx.var(name) {
+        Ok(value) => value.to_string().parse::<T>().unwrap_or_else(|e| {
+            error!(
+                "Failed to parse {} - falling back to default {} - {}",
+                name, default, e
+            );
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+// The worker's `OutputLimits`, from the optional `MIN_OUTPUT_DIMENSION` and
+// `MAX_OUTPUT_DIMENSION` vars. These should match the origin server's, as it
+// serves the same images when the worker fails over
+fn output_limits<D>(ctx: &RouteContext<D>) -> image_params::OutputLimits {
+    let defaults = image_params::OutputLimits::default();
+
+    image_params::OutputLimits {
+        min_dimension: parse_var(ctx, "MIN_OUTPUT_DIMENSION", defaults.min_dimension),
+        max_dimension: parse_var(ctx, "MAX_OUTPUT_DIMENSION", defaults.max_dimension),
+    }
+}
+
+// The worker's `RandOptions` for unseeded random images, from the optional
+// `RANDOM_MIN_SCORE`, `RANDOM_MAX_ATTEMPTS` and `RANDOM_MAX_WORK` vars, like
+// the origin server's `MANDELATAR_RANDOM_*` ones
+fn rand_options<D>(ctx: &RouteContext<D>) -> image_params::RandOptions {
+    let defaults = image_params::RandOptions::default();
+
+    image_params::RandOptions {
+        min_score: parse_var(ctx, "RANDOM_MIN_SCORE", defaults.min_score),
+        max_attempts: parse_var(ctx, "RANDOM_MAX_ATTEMPTS", WORKER_MAX_RAND_ATTEMPTS),
+        max_work: parse_var(ctx, "RANDOM_MAX_WORK", defaults.max_work),
+        ..defaults
+    }
+}
+
 fn parse_image_q_params(
     req: &Request,
+    limits: &image_params::OutputLimits,
 ) -> std::result::Result<image_params::ImagePostProcessConfig, errors::UserError> {
     let url = req
         .url()
@@ -23,7 +74,7 @@ fn parse_image_q_params(
         .into_owned()
         .collect::<Vec<(String, String)>>();
 
-    image_params::ImagePostProcessConfig::from_query_params(&query_pairs).map_err(|_e| {
+    image_params::ImagePostProcessConfig::from_query_params(&query_pairs, limits).map_err(|_e| {
         errors::UserError::ValidationError {
             message: "Invalid query params provided".to_string(),
         }
@@ -70,22 +121,38 @@ fn preflight_get_response(
         .with_status(204))
 }
 
+// The `?seed=` option, for the same random image every time
+fn parse_seed_q_param(req: &Request) -> std::result::Result<Option<u64>, errors::UserError> {
+    let url = req
+        .url()
+        .map_err(|e| errors::UserError::WorkerError { error: e })?;
+
+    url.query_pairs()
+        .find(|(key, _)| key == "seed")
+        .map(|(_, seed)| seed.parse::<u64>())
+        .transpose()
+        .map_err(|_e| errors::UserError::ValidationError {
+            message: "Invalid seed provided".to_string(),
+        })
+}
+
 fn get_random_image<D>(
     req: Request,
     ctx: RouteContext<D>,
 ) -> ApiResult<Response, errors::UserError> {
-    let img_params = image_params::ImageParams::new_from_rand((
-        image_params::OUTPUT_WIDTH,
-        image_params::OUTPUT_HEIGHT,
-    ));
+    let bounds = (image_params::OUTPUT_WIDTH, image_params::OUTPUT_HEIGHT);
+    let img_params = match parse_seed_q_param(&req)? {
+        // Seeds give the same image as on the origin server, so they keep the
+        // default options
+        Some(seed) => image_params::ImageParams::new_from_seed(bounds, seed),
+        None => image_params::ImageParams::new_from_rand_with_options(bounds, &rand_options(&ctx)),
+    };
 
-    let imp_bin = bincode::serialize(&img_params).map_err(|e| {
+    let b64 = img_params.to_token().map_err(|e| {
         error!("Failed to serialize img params: {}", e);
         errors::UserError::InternalError
     })?;
 
-    let b64 = base64::encode_config(imp_bin, base64::URL_SAFE);
-
     let mut new_url = req.url().map_err::<errors::UserError, _>(|e| e.into())?;
     new_url.set_path(format!("i1/i/{}.png", b64).as_str());
 
@@ -110,7 +177,7 @@ async fn get_image<D>(
         })?
         .to_owned();
 
-    if img_b64.trim().is_empty() || img_b64.len() > MAX_B64_LEN {
+    if img_b64.trim().is_empty() || img_b64.len() > image_params::MAX_TOKEN_LEN {
         return Err(errors::UserError::ValidationError {
             message: "invalid base64 provided".to_string(),
         });
@@ -118,27 +185,63 @@ async fn get_image<D>(
 
     let img_b64 = img_b64.replace(".png", "");
 
-    let bin = base64::decode_config(img_b64, base64::URL_SAFE).map_err(|e| {
-        error!("Failed to decode from b64: {}", e);
-        errors::UserError::ValidationError {
-            message: "Invalid base64 provided".to_string(),
-        }
-    })?;
-
-    let img_params: image_params::ImageParams = bincode::deserialize(&bin).map_err(|e| {
+    let img_params = image_params::ImageParams::from_token(&img_b64).map_err(|e| {
         error!("Failed to deserialize from b64: {}", e);
         errors::UserError::ValidationError {
             message: "Invalid base64 provided".to_string(),
         }
     })?;
 
-    let mut png_bytes = mandelbrot::create_png(&img_params).map_err(|e| {
+    render_image(img_params, req, ctx).await
+}
+
+async fn get_identity_image<D>(
+    req: Request,
+    ctx: RouteContext<D>,
+) -> ApiResult<Response, errors::UserError> {
+    // The router leaves the segment percent-encoded, unlike the origin server
+    let identity = ctx
+        .param("identity")
+        .ok_or(errors::UserError::ValidationError {
+            message: "invalid identity provided".to_string(),
+        })
+        .and_then(|segment| {
+            image_params::identity_from_encoded_path(segment).map_err(|e| {
+                error!("Invalid identity: {}", e);
+                errors::UserError::ValidationError {
+                    message: "invalid identity provided".to_string(),
+                }
+            })
+        })?;
+
+    let img_params = image_params::ImageParams::from_identity(&identity);
+
+    render_image(img_params, req, ctx).await
+}
+
+async fn render_image<D>(
+    mut img_params: image_params::ImageParams,
+    req: Request,
+    ctx: RouteContext<D>,
+) -> ApiResult<Response, errors::UserError> {
+    let limits = output_limits(&ctx);
+    let mut q_params = parse_image_q_params(&req, &limits)?;
+    q_params.apply_to_image_params(&mut img_params, &limits);
+
+    if img_params
+        .validate_work(&limits, MAX_WORKER_RENDER_WORK)
+        .is_err()
+    {
+        // The origin server serves the same `/i1/...` routes, and a worker's
+        // requests to its own route skip the worker
+        return Ok(Fetch::Request(req).send().await?);
+    }
+
+    let mut png_bytes = mandelbrot::create_png_within(&img_params, &limits).map_err(|e| {
         error!("Failed to create image: {}", e);
         errors::UserError::InternalError
     })?;
 
-    let q_params = parse_image_q_params(&req)?;
-
     if q_params.should_post_process() {
         let kv_store = ctx.kv("MANDELATAR_ASSETS")?;
         png_bytes = post_processing::process_from_params(q_params, &mut png_bytes, &kv_store)
@@ -194,4 +297,13 @@ pub fn apply_routes<'a, D: 'a>(router: Router<'a, D>) -> Router<'a, D> {
                 &ctx.var("CORS_ORIGIN")?.to_string(),
             ))
         })
+        .get_async("/i1/id/:identity", |req, ctx| async move {
+            to_worker_result(get_identity_image(req, ctx).await)
+        })
+        .options("/i1/id/:identity", |req, ctx| {
+            to_worker_result(preflight_get_response(
+                req.headers(),
+                &ctx.var("CORS_ORIGIN")?.to_string(),
+            ))
+        })
 }